@@ -0,0 +1,109 @@
+use std::pin::Pin;
+use std::time::Duration;
+
+use btleplug::api::{
+    Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, ValueNotification,
+    WriteType,
+};
+use btleplug::platform::{Manager, Peripheral};
+use futures::{Stream, StreamExt};
+use log::info;
+use uuid::Uuid;
+
+use crate::protocol::{Device, Status, CONTROL_UUID, PACKET_UUID, STATUS_UUID};
+use crate::Result;
+
+// Advertised by every Thingy, see thingy-control/src/ble.rs
+const CONTROL_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000dad0_0000_0000_0000_000000000000);
+
+// A Thingy connected over BLE. No pairing is needed, the image signature
+// is what keeps others from installing their firmware.
+pub struct BleDevice {
+    peripheral: Peripheral,
+    control: Characteristic,
+    packet: Characteristic,
+    notifications: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+}
+
+impl BleDevice {
+    // Connects to the first Thingy whose name starts with `name`
+    pub async fn connect(name: &str) -> Result<Self> {
+        let manager = Manager::new().await?;
+        let adapter = manager
+            .adapters()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or("no bluetooth adapter")?;
+
+        info!("scanning for {}...", name);
+        adapter
+            .start_scan(ScanFilter {
+                services: vec![CONTROL_SERVICE_UUID],
+            })
+            .await?;
+        let peripheral = 'scan: loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            for peripheral in adapter.peripherals().await? {
+                let local_name = peripheral.properties().await?.and_then(|p| p.local_name);
+                if local_name.is_some_and(|local_name| local_name.starts_with(name)) {
+                    break 'scan peripheral;
+                }
+            }
+        };
+        adapter.stop_scan().await?;
+
+        peripheral.connect().await?;
+        peripheral.discover_services().await?;
+        info!("connected to {}", peripheral.address());
+
+        let characteristic = |uuid: Uuid| {
+            peripheral
+                .characteristics()
+                .into_iter()
+                .find(|c| c.uuid == uuid)
+                .ok_or(format!(
+                    "no characteristic {}, is it an updatable firmware?",
+                    uuid
+                ))
+        };
+        let control = characteristic(CONTROL_UUID)?;
+        let packet = characteristic(PACKET_UUID)?;
+        let status = characteristic(STATUS_UUID)?;
+
+        peripheral.subscribe(&status).await?;
+        let notifications = peripheral.notifications().await?;
+
+        Ok(BleDevice {
+            peripheral,
+            control,
+            packet,
+            notifications,
+        })
+    }
+}
+
+impl Device for BleDevice {
+    async fn control(&mut self, data: &[u8]) -> Result<()> {
+        Ok(self
+            .peripheral
+            .write(&self.control, data, WriteType::WithResponse)
+            .await?)
+    }
+
+    async fn packet(&mut self, data: &[u8]) -> Result<()> {
+        Ok(self
+            .peripheral
+            .write(&self.packet, data, WriteType::WithoutResponse)
+            .await?)
+    }
+
+    async fn status(&mut self) -> Result<Status> {
+        while let Some(notification) = self.notifications.next().await {
+            if notification.uuid == STATUS_UUID {
+                return Status::from_bytes(&notification.value);
+            }
+        }
+        Err("disconnected".into())
+    }
+}
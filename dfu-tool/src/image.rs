@@ -0,0 +1,77 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+use crate::Result;
+
+// Packaged image: magic, little endian u32 size, signature and the firmware
+const MAGIC: &[u8; 4] = b"TDFU";
+const HEADER_SIZE: usize = 4 + 4 + 64;
+
+// thingy-control/memory.x ACTIVE partition
+pub const MAX_IMAGE_SIZE: usize = 156 * 1024;
+
+pub struct Image {
+    pub firmware: Vec<u8>,
+    pub signature: [u8; 64],
+}
+
+// The bootloader checks the signature of the SHA-512 of the firmware
+fn digest(firmware: &[u8]) -> [u8; 64] {
+    Sha512::digest(firmware).into()
+}
+
+pub fn verify(firmware: &[u8], signature: &[u8; 64], public_key: &[u8; 32]) -> bool {
+    let Ok(key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    key.verify(&digest(firmware), &Signature::from_bytes(signature))
+        .is_ok()
+}
+
+impl Image {
+    // `firmware` is the raw binary from the start of the ACTIVE partition,
+    // e.g. `cargo objcopy --release -- -O binary thingy.bin`
+    pub fn sign(mut firmware: Vec<u8>, secret_key: &[u8; 32]) -> Result<Self> {
+        // The flash is written by words, erased flash reads as 0xFF
+        firmware.resize(firmware.len().next_multiple_of(4), 0xff);
+        if firmware.len() > MAX_IMAGE_SIZE {
+            return Err(format!(
+                "firmware is {} bytes, only {} fit",
+                firmware.len(),
+                MAX_IMAGE_SIZE
+            )
+            .into());
+        }
+
+        let key = SigningKey::from_bytes(secret_key);
+        let signature = key.sign(&digest(&firmware)).to_bytes();
+        Ok(Image {
+            firmware,
+            signature,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.firmware.len());
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&(self.firmware.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.signature);
+        buf.extend_from_slice(&self.firmware);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_SIZE || &buf[0..4] != MAGIC {
+            return Err("not a packaged image".into());
+        }
+        let size = u32::from_le_bytes(buf[4..8].try_into()?) as usize;
+        let firmware = buf[HEADER_SIZE..].to_vec();
+        if firmware.len() != size {
+            return Err(format!("truncated image, {} of {} bytes", firmware.len(), size).into());
+        }
+        Ok(Image {
+            firmware,
+            signature: buf[8..HEADER_SIZE].try_into()?,
+        })
+    }
+}
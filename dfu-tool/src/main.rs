@@ -0,0 +1,78 @@
+mod ble;
+mod image;
+mod protocol;
+mod simulated;
+
+use std::env;
+use std::fs;
+
+use ed25519_dalek::SigningKey;
+use log::{info, warn};
+use rand::rngs::OsRng;
+
+use ble::BleDevice;
+use image::Image;
+use protocol::{send, Device, ABORT};
+use simulated::SimulatedDevice;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const USAGE: &str = "usage:
+    dfu-tool keygen <key>                       writes the <key> secret and <key>.pub
+    dfu-tool package <firmware.bin> <key> <image.dfu>
+    dfu-tool send <image.dfu>                   to the first Thingy named DEVICE_NAME*
+    dfu-tool simulate <image.dfu> <key.pub>     to a simulated Thingy";
+
+const DEFAULT_DEVICE_NAME: &str = "Thingy Wii Control";
+
+fn read_key(path: &str) -> Result<[u8; 32]> {
+    fs::read(path)?
+        .try_into()
+        .map_err(|_| format!("{} is not a 32 bytes key", path).into())
+}
+
+// Aborts on the device too, so it doesn't wait for the rest of the image
+async fn send_or_abort<D: Device>(device: &mut D, image: &Image) -> Result<()> {
+    let result = send(device, image).await;
+    if result.is_err() && device.control(&[ABORT]).await.is_err() {
+        warn!("could not abort the update");
+    }
+    result
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["keygen", key] => {
+            let secret = SigningKey::generate(&mut OsRng);
+            fs::write(key, secret.to_bytes())?;
+            fs::write(format!("{}.pub", key), secret.verifying_key().to_bytes())?;
+            info!("keep {} secret, build the firmware with {}.pub", key, key);
+        }
+        ["package", firmware, key, output] => {
+            let image = Image::sign(fs::read(firmware)?, &read_key(key)?)?;
+            fs::write(output, image.to_bytes())?;
+            info!("{} bytes signed into {}", image.firmware.len(), output);
+        }
+        ["send", image] => {
+            let image = Image::from_bytes(&fs::read(image)?)?;
+            let name = env::var("DEVICE_NAME").unwrap_or(DEFAULT_DEVICE_NAME.to_owned());
+            let mut device = BleDevice::connect(&name).await?;
+            send_or_abort(&mut device, &image).await?;
+        }
+        ["simulate", image, public_key] => {
+            let image = Image::from_bytes(&fs::read(image)?)?;
+            let mut device = SimulatedDevice::new(read_key(public_key)?);
+            send_or_abort(&mut device, &image).await?;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use log::{debug, info};
+use uuid::Uuid;
+
+use crate::image::Image;
+use crate::Result;
+
+// DFU service of thingy-control/src/dfu.rs
+pub const CONTROL_UUID: Uuid = Uuid::from_u128(0x0000dad6_0000_0000_0000_000000000001);
+pub const PACKET_UUID: Uuid = Uuid::from_u128(0x0000dad6_0000_0000_0000_000000000002);
+pub const STATUS_UUID: Uuid = Uuid::from_u128(0x0000dad6_0000_0000_0000_000000000003);
+
+// Control opcodes
+pub const START: u8 = 0x01;
+pub const FINISH: u8 = 0x02;
+pub const ABORT: u8 = 0x03;
+
+// Chunks the device queues before writing them
+pub const WINDOW: usize = 4;
+// Data after the u32 offset of each packet, a multiple of 4
+pub const CHUNK_SIZE: usize = 240;
+
+// Erasing the whole partition takes seconds, writing a chunk much less
+const ERASE_TIMEOUT: Duration = Duration::from_secs(10);
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    Receiving,
+    Verified,
+    Failed,
+}
+
+// Notified after every command, with the bytes written so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub state: State,
+    pub offset: u32,
+}
+
+impl Status {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let [state, offset @ ..] = buf else {
+            return Err("empty status".into());
+        };
+        let state = match state {
+            0 => State::Idle,
+            1 => State::Receiving,
+            2 => State::Verified,
+            3 => State::Failed,
+            _ => return Err(format!("invalid state {}", state).into()),
+        };
+        Ok(Status {
+            state,
+            offset: u32::from_le_bytes(offset.try_into()?),
+        })
+    }
+}
+
+// A Thingy over BLE or the simulated one
+pub trait Device {
+    async fn control(&mut self, data: &[u8]) -> Result<()>;
+    async fn packet(&mut self, data: &[u8]) -> Result<()>;
+    async fn status(&mut self) -> Result<Status>;
+}
+
+async fn expect<D: Device>(device: &mut D, state: State, timeout: Duration) -> Result<Status> {
+    let status = tokio::time::timeout(timeout, device.status())
+        .await
+        .map_err(|_| "no status from the device")??;
+    debug!("status: {:?}", status);
+    if status.state != state {
+        return Err(format!("expected {:?}, the device is {:?}", state, status.state).into());
+    }
+    Ok(status)
+}
+
+// Sends the image in order, keeping up to WINDOW chunks unacknowledged.
+// The device reboots into the new firmware once it's verified.
+pub async fn send<D: Device>(device: &mut D, image: &Image) -> Result<()> {
+    let size = image.firmware.len();
+    let mut start = vec![START];
+    start.extend_from_slice(&(size as u32).to_le_bytes());
+    device.control(&start).await?;
+    info!("erasing...");
+    expect(device, State::Receiving, ERASE_TIMEOUT).await?;
+
+    let mut chunks = image.firmware.chunks(CHUNK_SIZE).enumerate();
+    let mut in_flight = 0;
+    let mut acknowledged = 0;
+    while acknowledged < size {
+        while in_flight < WINDOW {
+            let Some((i, chunk)) = chunks.next() else {
+                break;
+            };
+            let mut packet = ((i * CHUNK_SIZE) as u32).to_le_bytes().to_vec();
+            packet.extend_from_slice(chunk);
+            device.packet(&packet).await?;
+            in_flight += 1;
+        }
+
+        let status = expect(device, State::Receiving, STATUS_TIMEOUT).await?;
+        acknowledged = status.offset as usize;
+        in_flight -= 1;
+        if acknowledged.is_multiple_of(16 * CHUNK_SIZE) || acknowledged == size {
+            info!("{} / {} bytes", acknowledged, size);
+        }
+    }
+
+    let mut finish = vec![FINISH];
+    finish.extend_from_slice(&image.signature);
+    device.control(&finish).await?;
+    expect(device, State::Verified, ERASE_TIMEOUT).await?;
+    info!("verified, the device reboots into the new firmware");
+    Ok(())
+}
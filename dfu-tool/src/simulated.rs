@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+
+use log::warn;
+
+use crate::image::{verify, MAX_IMAGE_SIZE};
+use crate::protocol::{Device, State, Status, ABORT, FINISH, START};
+use crate::Result;
+
+const ERASE_SIZE: usize = 4096;
+
+// Follows the checks of thingy-control/src/dfu.rs and the flash rules:
+// words are only written once erased, so a protocol error shows up here
+// the same as on a Thingy.
+pub struct SimulatedDevice {
+    public_key: [u8; 32],
+    flash: Vec<u8>,
+    size: Option<usize>,
+    received: usize,
+    notifications: VecDeque<Status>,
+}
+
+impl SimulatedDevice {
+    pub fn new(public_key: [u8; 32]) -> Self {
+        SimulatedDevice {
+            public_key,
+            flash: vec![0xff; MAX_IMAGE_SIZE + ERASE_SIZE], // DFU partition, one page larger
+            size: None,
+            received: 0,
+            notifications: VecDeque::new(),
+        }
+    }
+
+    fn start(&mut self, size: usize) -> State {
+        self.received = 0;
+        self.size = None;
+        if size == 0 || size > MAX_IMAGE_SIZE || !size.is_multiple_of(4) {
+            warn!("simulated: invalid size {}", size);
+            return State::Failed;
+        }
+        let end = size.next_multiple_of(ERASE_SIZE);
+        self.flash[..end].fill(0xff);
+        self.size = Some(size);
+        State::Receiving
+    }
+
+    fn chunk(&mut self, offset: usize, data: &[u8]) -> State {
+        let Some(size) = self.size else {
+            warn!("simulated: not receiving an image");
+            return State::Failed;
+        };
+        if offset != self.received || !data.len().is_multiple_of(4) || offset + data.len() > size {
+            warn!(
+                "simulated: unexpected chunk at {} of {} bytes",
+                offset,
+                data.len()
+            );
+            return State::Failed;
+        }
+        let region = &mut self.flash[offset..offset + data.len()];
+        if region.iter().any(|&byte| byte != 0xff) {
+            warn!("simulated: writing over flash not erased at {}", offset);
+            return State::Failed;
+        }
+        region.copy_from_slice(data);
+        self.received += data.len();
+        State::Receiving
+    }
+
+    fn finish(&mut self, signature: &[u8; 64]) -> State {
+        match self.size {
+            Some(size) if size == self.received => {
+                if verify(&self.flash[..size], signature, &self.public_key) {
+                    State::Verified
+                } else {
+                    warn!("simulated: invalid signature");
+                    State::Failed
+                }
+            }
+            _ => {
+                warn!("simulated: image not fully received");
+                State::Failed
+            }
+        }
+    }
+
+    fn notify(&mut self, state: State) {
+        if state != State::Receiving {
+            self.size = None;
+        }
+        self.notifications.push_back(Status {
+            state,
+            offset: self.received as u32,
+        });
+    }
+}
+
+impl Device for SimulatedDevice {
+    async fn control(&mut self, data: &[u8]) -> Result<()> {
+        let state = match data {
+            [START, size @ ..] if size.len() == 4 => {
+                self.start(u32::from_le_bytes(size.try_into()?) as usize)
+            }
+            [FINISH, signature @ ..] if signature.len() == 64 => self.finish(signature.try_into()?),
+            [ABORT] => State::Idle,
+            _ => {
+                // The firmware ignores it, without notifying
+                warn!("simulated: invalid control {:02x?}", data);
+                return Ok(());
+            }
+        };
+        self.notify(state);
+        Ok(())
+    }
+
+    async fn packet(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() <= 4 {
+            return Ok(());
+        }
+        let offset = u32::from_le_bytes(data[0..4].try_into()?) as usize;
+        let state = self.chunk(offset, &data[4..]);
+        self.notify(state);
+        Ok(())
+    }
+
+    async fn status(&mut self) -> Result<Status> {
+        self.notifications
+            .pop_front()
+            .ok_or_else(|| "the simulated device has nothing to notify".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::image::Image;
+    use crate::protocol::{send, CHUNK_SIZE, WINDOW};
+
+    const SECRET_KEY: [u8; 32] = [7; 32];
+
+    fn device() -> SimulatedDevice {
+        SimulatedDevice::new(
+            SigningKey::from_bytes(&SECRET_KEY)
+                .verifying_key()
+                .to_bytes(),
+        )
+    }
+
+    // More chunks than the window, the last one shorter
+    fn image() -> Image {
+        let firmware = (0..(2 * WINDOW + 1) * CHUNK_SIZE + 100)
+            .map(|i| i as u8)
+            .collect();
+        Image::sign(firmware, &SECRET_KEY).unwrap()
+    }
+
+    // Swaps the second and third packets, as a lost and resent write would
+    struct Reordering {
+        device: SimulatedDevice,
+        held: Option<Vec<u8>>,
+        packets: usize,
+    }
+
+    impl Device for Reordering {
+        async fn control(&mut self, data: &[u8]) -> Result<()> {
+            self.device.control(data).await
+        }
+
+        async fn packet(&mut self, data: &[u8]) -> Result<()> {
+            self.packets += 1;
+            match self.packets {
+                2 => {
+                    self.held = Some(data.to_vec());
+                    Ok(())
+                }
+                3 => {
+                    self.device.packet(data).await?;
+                    let held = self.held.take().unwrap();
+                    self.device.packet(&held).await
+                }
+                _ => self.device.packet(data).await,
+            }
+        }
+
+        async fn status(&mut self) -> Result<Status> {
+            self.device.status().await
+        }
+    }
+
+    #[tokio::test]
+    async fn good_image_is_verified() {
+        let image = image();
+        let mut device = device();
+
+        send(&mut device, &image).await.unwrap();
+        assert_eq!(&device.flash[..image.firmware.len()], &image.firmware[..]);
+        assert!(device.notifications.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_signature_fails() {
+        let mut image = image();
+        image.signature[0] ^= 1;
+        let mut device = device();
+
+        let error = send(&mut device, &image).await.unwrap_err();
+        assert_eq!(error.to_string(), "expected Verified, the device is Failed");
+        assert_eq!(device.size, None);
+    }
+
+    #[tokio::test]
+    async fn out_of_order_chunk_fails() {
+        let image = image();
+        let mut device = Reordering {
+            device: device(),
+            held: None,
+            packets: 0,
+        };
+
+        let error = send(&mut device, &image).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "expected Receiving, the device is Failed"
+        );
+        // Only the first chunk was written
+        assert_eq!(device.device.received, CHUNK_SIZE);
+        assert!(device.device.flash[CHUNK_SIZE..].iter().all(|&b| b == 0xff));
+    }
+}
@@ -15,6 +15,7 @@ class HomePage extends StatefulWidget {
 
 class _HomePageState extends State<HomePage> {
   static const rabbitMqHost = "145.126.47.164";
+  // Every Thingy appends part of its address, e.g. "Thingy Wii Control 1A2B"
   static const deviceName = "Thingy Wii Control";
 
   bool _scanning = true;
@@ -43,7 +44,7 @@ class _HomePageState extends State<HomePage> {
 
             var devices = results
                 .where(
-                    (e) => e.advertisementData.localName == deviceName)
+                    (e) => e.advertisementData.localName.startsWith(deviceName))
                 .map((e) => e.device)
                 .toSet();
 
@@ -72,8 +73,43 @@ class _HomePageState extends State<HomePage> {
                 for (var service in services) {
                 logger.i('Looking service ${service.uuid}');
                   var characteristics = service.characteristics;
+                  // Commands (write only characteristics, e.g. the crash acknowledge) are consumed
+                  // from their queue and written, the queues exist before any value is forwarded
+                  for(var characteristic in characteristics) {
+                      var properties = characteristic.properties;
+                      if (properties.write && !properties.read && !properties.notify) {
+                          logger.i('Found write characteristic ${characteristic.uuid}');
+                          var queue = await channel.queue(
+                            "${device.remoteId}/${service.uuid}/${characteristic.uuid}",
+                            arguments: {
+                              "x-message-ttl": 1000,
+                            }
+                          );
+                          var consumer = await queue.consume();
+                          consumer.listen((AmqpMessage message) async {
+                            logger.i('Writing value ${message.payload} to ${characteristic.uuid}');
+                            await characteristic.write(message.payload!.toList());
+                          });
+                          device.connectionState
+                              .firstWhere((state) => state == BluetoothConnectionState.disconnected)
+                              .then((_) => consumer.cancel());
+                      }
+                  }
                   for(var characteristic in characteristics) {
                       logger.i('Looking characteristic ${characteristic.uuid}');
+                      if (characteristic.properties.read && !characteristic.properties.notify) {
+                          // Static values (e.g. device information) are forwarded once on connect
+                          logger.i('Found read characteristic ${characteristic.uuid}');
+                          var queue = await channel.queue(
+                            "${device.remoteId}/${service.uuid}/${characteristic.uuid}",
+                            arguments: {
+                              "x-message-ttl": 1000,
+                            }
+                          );
+                          var value = await characteristic.read();
+                          logger.i('Read value $value from ${characteristic.uuid}');
+                          queue.publish(Uint8List.fromList(value));
+                      }
                       if (characteristic.properties.notify) {
                           logger.i('Found notify characteristic ${characteristic.uuid}');
                           var queue = await channel.queue(
@@ -0,0 +1,108 @@
+// Shared with the firmware, so the features are computed the same way
+#[path = "../../thingy-control/src/features.rs"]
+mod features;
+#[allow(dead_code, clippy::wrong_self_convention)]
+#[path = "../../thingy-control/src/fusion.rs"]
+mod fusion;
+
+mod recording;
+mod tree;
+
+use std::env;
+use std::fs;
+
+use log::{info, warn};
+
+use features::FEATURE_COUNT;
+use recording::{Sample, GESTURES};
+use tree::Tree;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// thingy-control/src/model.rs refuses deeper trees
+const MAX_DEPTH: usize = 10;
+const DEFAULT_DEPTH: usize = 6;
+// Fewer samples than this (50 ms at 200 Hz) aren't worth a leaf
+const MIN_LEAF_SAMPLES: usize = 10;
+
+// Order of thingy-control/src/features.rs
+const FEATURE_NAMES: [&str; FEATURE_COUNT] = [
+    "pitch", "roll", "heading", "ax", "ay", "az", "gx", "gy", "gz",
+];
+
+const USAGE: &str = "usage: gesture-trainer <gesture_model.rs> <recording.csv>...";
+
+fn generate(trees: &[Tree], samples: usize, recordings: usize) -> String {
+    let depth = trees.iter().map(|tree| tree.depth).max().unwrap_or(0);
+    let mut source = format!(
+        "// Generated by gesture-trainer from {} samples of {} recordings,\n\
+         // retrain the model instead of editing it.\n\
+         pub const MODEL: Option<Model> = Some(Model {{\n    depth: {},\n",
+        samples, recordings, depth
+    );
+    for (gesture, tree) in GESTURES.iter().zip(trees) {
+        source += &format!("    {}: {},\n", gesture, tree.to_rust(&FEATURE_NAMES));
+    }
+    source += "});\n";
+    source
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [output, recordings @ ..] = &args[..] else {
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    };
+    if recordings.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+
+    let depth = match env::var("TREE_DEPTH") {
+        Ok(depth) => depth.parse()?,
+        Err(_) => DEFAULT_DEPTH,
+    };
+    if depth > MAX_DEPTH {
+        return Err(format!(
+            "TREE_DEPTH is {}, the firmware takes up to {}",
+            depth, MAX_DEPTH
+        )
+        .into());
+    }
+
+    let mut samples: Vec<Sample> = Vec::new();
+    for recording in recordings {
+        let loaded = recording::load(recording)?;
+        info!("{}: {} samples", recording, loaded.len());
+        samples.extend(loaded);
+    }
+    let features: Vec<[f32; FEATURE_COUNT]> = samples.iter().map(|s| s.features).collect();
+
+    let mut trees = Vec::new();
+    for (i, gesture) in GESTURES.iter().enumerate() {
+        let labels: Vec<i8> = samples.iter().map(|s| s.labels[i]).collect();
+        if labels.iter().all(|&label| label == 0) {
+            warn!("{}: never labelled, it will never be detected", gesture);
+        }
+        let tree = Tree::fit(&features, &labels, depth, MIN_LEAF_SAMPLES);
+        let correct = features
+            .iter()
+            .zip(&labels)
+            .filter(|(features, &label)| tree.predict(features) == label)
+            .count();
+        info!(
+            "{}: {} nodes, depth {}, {:.1}% of the samples right",
+            gesture,
+            tree.nodes.len(),
+            tree.depth,
+            100.0 * correct as f32 / labels.len() as f32
+        );
+        trees.push(tree);
+    }
+
+    fs::write(output, generate(&trees, samples.len(), recordings.len()))?;
+    info!("model written to {}", output);
+    Ok(())
+}
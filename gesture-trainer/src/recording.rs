@@ -0,0 +1,128 @@
+use std::fs;
+
+use crate::features::{align_mag, features, wrap_angle, FEATURE_COUNT};
+use crate::fusion::Madgwick;
+use crate::Result;
+
+// Same as thingy-control/src/main.rs
+const FUSION_BETA: f32 = 0.1;
+// Samples before the orientation converges aren't learned from
+const WARM_UP_US: u64 = 1_000_000;
+
+// The raw IMU stream in SI units (m/s², rad/s and µT in the AK8963 axes),
+// calibrated on the device like the samples it classifies,
+// then the gestures encoded like the control characteristics
+const COLUMNS: [&str; 15] = [
+    "timestamp_us",
+    "ax",
+    "ay",
+    "az",
+    "gx",
+    "gy",
+    "gz",
+    "mx",
+    "my",
+    "mz",
+    "left_right",
+    "up_down",
+    "jump",
+    "spin",
+    "turn",
+];
+
+// Trees of the model, in the label columns order
+pub const GESTURES: [&str; 5] = ["left_right", "up_down", "jump", "spin", "turn"];
+
+pub struct Sample {
+    pub features: [f32; FEATURE_COUNT],
+    pub labels: [i8; GESTURES.len()],
+}
+
+struct Row {
+    timestamp: u64,
+    accel: (f32, f32, f32),
+    gyro: (f32, f32, f32),
+    mag: (f32, f32, f32),
+    labels: [i8; GESTURES.len()],
+}
+
+fn parse_row(line: &str) -> Result<Row> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() != COLUMNS.len() {
+        return Err(format!("{} columns instead of {}", fields.len(), COLUMNS.len()).into());
+    }
+
+    let value = |i: usize| -> Result<f32> {
+        let value: f32 = fields[i].parse()?;
+        if !value.is_finite() {
+            return Err(format!("{} is {}", COLUMNS[i], value).into());
+        }
+        Ok(value)
+    };
+    let mut labels = [0; GESTURES.len()];
+    for (i, label) in labels.iter_mut().enumerate() {
+        let column = 10 + i;
+        *label = fields[column].parse()?;
+        // jump and spin are booleans, the others go both ways
+        let min = if matches!(GESTURES[i], "jump" | "spin") {
+            0
+        } else {
+            -1
+        };
+        if !(min..=1).contains(label) {
+            return Err(format!("{} is {}", COLUMNS[column], label).into());
+        }
+    }
+
+    Ok(Row {
+        timestamp: fields[0].parse()?,
+        accel: (value(1)?, value(2)?, value(3)?),
+        gyro: (value(4)?, value(5)?, value(6)?),
+        mag: (value(7)?, value(8)?, value(9)?),
+        labels,
+    })
+}
+
+// Replays a continuous recording through the orientation filter like the
+// control task, the heading is relative to the end of the warm up.
+pub fn load(path: &str) -> Result<Vec<Sample>> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines().enumerate();
+    let header: Vec<&str> = match lines.next() {
+        Some((_, header)) => header.split(',').map(str::trim).collect(),
+        None => return Err(format!("{} is empty", path).into()),
+    };
+    if header != COLUMNS {
+        return Err(format!("{}: the header must be {}", path, COLUMNS.join(",")).into());
+    }
+    let rows = lines
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_row(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e).into()))
+        .collect::<Result<Vec<Row>>>()?;
+
+    let (first, last) = match (rows.first(), rows.last()) {
+        (Some(first), Some(last)) if rows.len() > 1 && last.timestamp > first.timestamp => {
+            (first.timestamp, last.timestamp)
+        }
+        _ => return Err(format!("{} has too few samples", path).into()),
+    };
+    let sample_period = (last - first) as f32 / 1e6 / (rows.len() - 1) as f32;
+
+    let mut filter = Madgwick::new(sample_period, FUSION_BETA);
+    let mut reference_heading = None;
+    let mut samples = Vec::new();
+    for row in rows {
+        filter.update_marg(row.gyro, row.accel, align_mag(row.mag));
+        if row.timestamp.saturating_sub(first) < WARM_UP_US {
+            continue;
+        }
+        let orientation = filter.quaternion();
+        let reference = *reference_heading.get_or_insert(orientation.yaw());
+        let heading = wrap_angle(orientation.yaw() - reference);
+        samples.push(Sample {
+            features: features(orientation.gravity(), heading, row.accel, row.gyro),
+            labels: row.labels,
+        });
+    }
+    Ok(samples)
+}
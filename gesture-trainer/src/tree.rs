@@ -0,0 +1,317 @@
+use std::fmt::Write;
+
+use crate::features::FEATURE_COUNT;
+
+// Classes of every gesture, encoded like the control characteristics
+const CLASSES: [i8; 3] = [0, -1, 1]; // ties go to the neutral one
+
+fn class_index(class: i8) -> usize {
+    (class + 1) as usize
+}
+
+type Counts = [usize; CLASSES.len()];
+
+fn counts(labels: impl Iterator<Item = i8>) -> Counts {
+    let mut counts = [0; CLASSES.len()];
+    for label in labels {
+        counts[class_index(label)] += 1;
+    }
+    counts
+}
+
+fn gini(counts: &Counts) -> f32 {
+    let total: usize = counts.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    1.0 - counts
+        .iter()
+        .map(|&count| (count as f32 / total as f32).powi(2))
+        .sum::<f32>()
+}
+
+fn majority(counts: &Counts) -> i8 {
+    *CLASSES
+        .iter()
+        .rev()
+        .max_by_key(|&&class| counts[class_index(class)])
+        .unwrap()
+}
+
+// Same layout as thingy-control/src/model.rs, the root first
+pub enum Node {
+    Split {
+        feature: usize,
+        threshold: f32,
+        left: usize,
+        right: usize,
+    },
+    Leaf(i8),
+}
+
+pub struct Tree {
+    pub nodes: Vec<Node>,
+    pub depth: usize,
+}
+
+struct Split {
+    feature: usize,
+    threshold: f32,
+    impurity: f32,
+}
+
+// Lowest weighted gini impurity splitting on `feature`, keeping `min_leaf`
+// samples on each side
+fn best_split_on(
+    feature: usize,
+    features: &[[f32; FEATURE_COUNT]],
+    labels: &[i8],
+    indices: &[usize],
+    min_leaf: usize,
+) -> Option<Split> {
+    let mut sorted = indices.to_vec();
+    sorted.sort_by(|&a, &b| features[a][feature].total_cmp(&features[b][feature]));
+
+    let total = counts(indices.iter().map(|&i| labels[i]));
+    let mut left = [0; CLASSES.len()];
+    let mut best: Option<Split> = None;
+    for i in 1..sorted.len() {
+        left[class_index(labels[sorted[i - 1]])] += 1;
+        let below = features[sorted[i - 1]][feature];
+        let above = features[sorted[i]][feature];
+        if i < min_leaf || sorted.len() - i < min_leaf || below == above {
+            continue;
+        }
+        let right: Counts = core::array::from_fn(|class| total[class] - left[class]);
+        let impurity = (i as f32 * gini(&left) + (sorted.len() - i) as f32 * gini(&right))
+            / sorted.len() as f32;
+        if best.as_ref().is_none_or(|best| impurity < best.impurity) {
+            best = Some(Split {
+                feature,
+                threshold: below + (above - below) / 2.0,
+                impurity,
+            });
+        }
+    }
+    best
+}
+
+fn best_split(
+    features: &[[f32; FEATURE_COUNT]],
+    labels: &[i8],
+    indices: &[usize],
+    min_leaf: usize,
+) -> Option<Split> {
+    (0..FEATURE_COUNT)
+        .filter_map(|feature| best_split_on(feature, features, labels, indices, min_leaf))
+        .min_by(|a, b| a.impurity.total_cmp(&b.impurity))
+}
+
+impl Tree {
+    // CART classification tree of `max_depth` splits at most
+    pub fn fit(
+        features: &[[f32; FEATURE_COUNT]],
+        labels: &[i8],
+        max_depth: usize,
+        min_leaf: usize,
+    ) -> Self {
+        let mut tree = Tree {
+            nodes: Vec::new(),
+            depth: 0,
+        };
+        let indices: Vec<usize> = (0..labels.len()).collect();
+        tree.grow(features, labels, &indices, 0, max_depth, min_leaf);
+        tree
+    }
+
+    fn grow(
+        &mut self,
+        features: &[[f32; FEATURE_COUNT]],
+        labels: &[i8],
+        indices: &[usize],
+        depth: usize,
+        max_depth: usize,
+        min_leaf: usize,
+    ) -> usize {
+        let index = self.nodes.len();
+        let counts = counts(indices.iter().map(|&i| labels[i]));
+        self.nodes.push(Node::Leaf(majority(&counts)));
+        self.depth = self.depth.max(depth);
+
+        let impurity = gini(&counts);
+        if depth == max_depth || impurity == 0.0 {
+            return index;
+        }
+        let Some(split) = best_split(features, labels, indices, min_leaf) else {
+            return index;
+        };
+        if split.impurity >= impurity {
+            return index;
+        }
+
+        let (below, above): (Vec<usize>, Vec<usize>) = indices
+            .iter()
+            .partition(|&&i| features[i][split.feature] < split.threshold);
+        let left = self.grow(features, labels, &below, depth + 1, max_depth, min_leaf);
+        let right = self.grow(features, labels, &above, depth + 1, max_depth, min_leaf);
+        self.nodes[index] = Node::Split {
+            feature: split.feature,
+            threshold: split.threshold,
+            left,
+            right,
+        };
+        index
+    }
+
+    pub fn predict(&self, features: &[f32; FEATURE_COUNT]) -> i8 {
+        let mut index = 0;
+        loop {
+            match self.nodes[index] {
+                Node::Split {
+                    feature,
+                    threshold,
+                    left,
+                    right,
+                } => {
+                    index = if features[feature] < threshold {
+                        left
+                    } else {
+                        right
+                    }
+                }
+                Node::Leaf(class) => return class,
+            }
+        }
+    }
+
+    // `&[Node]` for the firmware, with the feature names as comments
+    pub fn to_rust(&self, feature_names: &[&str; FEATURE_COUNT]) -> String {
+        let mut source = String::from("&[\n");
+        for node in &self.nodes {
+            match node {
+                Node::Split {
+                    feature,
+                    threshold,
+                    left,
+                    right,
+                } => writeln!(
+                    source,
+                    "        Node::Split {{ feature: {}, threshold: {:?}, left: {}, right: {} }}, // {}",
+                    feature, threshold, left, right, feature_names[*feature]
+                ),
+                Node::Leaf(class) => writeln!(source, "        Node::Leaf({}),", class),
+            }
+            .unwrap();
+        }
+        source.push_str("    ]");
+        source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FEATURE_NAMES, MAX_DEPTH};
+
+    // Roll from -1 to 1 rad: left beyond 0.3, right below -0.3,
+    // the other features are noise the tree must ignore
+    fn tilted(samples: usize) -> (Vec<[f32; FEATURE_COUNT]>, Vec<i8>) {
+        let mut seed: u32 = 1;
+        let mut noise = || {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        };
+        let mut features = Vec::new();
+        let mut labels = Vec::new();
+        for i in 0..samples {
+            let roll = -1.0 + 2.0 * i as f32 / (samples - 1) as f32;
+            let mut sample: [f32; FEATURE_COUNT] = core::array::from_fn(|_| noise());
+            sample[1] = roll;
+            features.push(sample);
+            labels.push(match roll {
+                roll if roll > 0.3 => 1,
+                roll if roll < -0.3 => -1,
+                _ => 0,
+            });
+        }
+        (features, labels)
+    }
+
+    fn depth(nodes: &[Node], index: usize) -> usize {
+        match nodes[index] {
+            Node::Split { left, right, .. } => 1 + depth(nodes, left).max(depth(nodes, right)),
+            Node::Leaf(_) => 0,
+        }
+    }
+
+    #[test]
+    fn fit_separates_the_classes() {
+        let (features, labels) = tilted(400);
+        let tree = Tree::fit(&features, &labels, 6, 1);
+
+        for (features, &label) in features.iter().zip(&labels) {
+            assert_eq!(tree.predict(features), label);
+        }
+        // Two splits on the roll are enough
+        assert_eq!(tree.depth, 2);
+        assert!(tree.nodes.iter().all(|node| match node {
+            Node::Split { feature, .. } => *feature == 1,
+            Node::Leaf(_) => true,
+        }));
+
+        let mut unseen = [0.0; FEATURE_COUNT];
+        for (roll, class) in [(0.5, 1), (0.0, 0), (-0.5, -1)] {
+            unseen[1] = roll;
+            assert_eq!(tree.predict(&unseen), class);
+        }
+    }
+
+    // Alternating classes every sample would take a split each
+    fn alternating() -> (Vec<[f32; FEATURE_COUNT]>, Vec<i8>) {
+        let (features, _) = tilted(4096);
+        let labels = (0..features.len()).map(|i| (i % 2) as i8).collect();
+        (features, labels)
+    }
+
+    #[test]
+    fn depth_is_bounded() {
+        let (features, labels) = alternating();
+        let tree = Tree::fit(&features, &labels, MAX_DEPTH, 1);
+
+        assert_eq!(depth(&tree.nodes, 0), tree.depth);
+        assert_eq!(tree.depth, MAX_DEPTH);
+    }
+
+    #[test]
+    fn to_rust_lists_every_node_within_max_depth() {
+        let (features, labels) = alternating();
+        let tree = Tree::fit(&features, &labels, MAX_DEPTH, 1);
+        let source = tree.to_rust(&FEATURE_NAMES);
+
+        let nodes: Vec<&str> = source
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with("Node::"))
+            .collect();
+        assert_eq!(nodes.len(), tree.nodes.len());
+        assert!(source.starts_with("&[\n") && source.ends_with("]"));
+
+        // Children indices in the generated source, so the firmware walks the same tree
+        let field = |line: &str, name: &str| -> usize {
+            let start = line.find(name).unwrap() + name.len();
+            let end = start + line[start..].find(|c: char| !c.is_ascii_digit()).unwrap();
+            line[start..end].parse().unwrap()
+        };
+        let mut depths = vec![0; nodes.len()];
+        for (index, line) in nodes.iter().enumerate() {
+            if line.starts_with("Node::Split") {
+                for child in [field(line, "left: "), field(line, "right: ")] {
+                    assert!(child > index && child < nodes.len());
+                    depths[child] = depths[index] + 1;
+                }
+                assert!(line.ends_with(FEATURE_NAMES[field(line, "feature: ")]));
+            }
+        }
+        assert_eq!(depths.into_iter().max(), Some(MAX_DEPTH));
+    }
+}
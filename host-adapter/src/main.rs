@@ -4,11 +4,11 @@ use std::sync::{Arc, Mutex};
 use evdev::{uinput::VirtualDeviceBuilder, AttributeSet, InputEvent, KeyCode, KeyEvent};
 use lapin::{
     message::DeliveryResult,
-    options::{BasicAckOptions, BasicConsumeOptions},
+    options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions},
     types::FieldTable,
-    Channel, Connection, ConnectionProperties, Consumer,
+    BasicProperties, Channel, Connection, ConnectionProperties, Consumer,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 
 #[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
 enum LeftRight {
@@ -18,13 +18,25 @@ enum LeftRight {
     Right,
 }
 
-impl From<i8> for LeftRight {
-    fn from(lr: i8) -> Self {
+// A 2 bits direction field holding 3, which no firmware sends
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InvalidDirection(u16);
+
+impl std::fmt::Display for InvalidDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid direction {}", self.0)
+    }
+}
+
+impl TryFrom<u16> for LeftRight {
+    type Error = InvalidDirection;
+
+    fn try_from(lr: u16) -> Result<Self, Self::Error> {
         match lr {
-            1 => LeftRight::Left,
-            0 => LeftRight::None,
-            -1 => LeftRight::Right,
-            _ => panic!("Invalid value for LeftRight"),
+            0 => Ok(LeftRight::None),
+            1 => Ok(LeftRight::Left),
+            2 => Ok(LeftRight::Right),
+            _ => Err(InvalidDirection(lr)),
         }
     }
 }
@@ -37,13 +49,15 @@ enum UpDown {
     Down,
 }
 
-impl From<i8> for UpDown {
-    fn from(ud: i8) -> Self {
+impl TryFrom<u16> for UpDown {
+    type Error = InvalidDirection;
+
+    fn try_from(ud: u16) -> Result<Self, Self::Error> {
         match ud {
-            -1 => UpDown::Up,
-            0 => UpDown::None,
-            1 => UpDown::Down,
-            _ => panic!("Invalid value for UpDown"),
+            0 => Ok(UpDown::None),
+            1 => Ok(UpDown::Up),
+            2 => Ok(UpDown::Down),
+            _ => Err(InvalidDirection(ud)),
         }
     }
 }
@@ -55,24 +69,78 @@ pub struct Control {
     shoot: bool,
     jump: bool,
     spin: bool,
+    turn: LeftRight,
+    double_click: bool,
+    long_press: bool,
+}
+
+// Bitfield from the State characteristic:
+// bits 0-1 left_right, bits 2-3 up_down, bit 4 shoot, bit 5 jump, bit 6 spin, bits 7-8 turn,
+// bit 9 double click and bit 10 long press
+impl TryFrom<u16> for Control {
+    type Error = InvalidDirection;
+
+    fn try_from(bits: u16) -> Result<Self, Self::Error> {
+        Ok(Control {
+            left_right: (bits & 0b11).try_into()?,
+            up_down: ((bits >> 2) & 0b11).try_into()?,
+            shoot: bits & (1 << 4) != 0,
+            jump: bits & (1 << 5) != 0,
+            spin: bits & (1 << 6) != 0,
+            turn: ((bits >> 7) & 0b11).try_into()?,
+            double_click: bits & (1 << 9) != 0,
+            long_press: bits & (1 << 10) != 0,
+        })
+    }
 }
 
 const DEVICE_ID: &str = "DF:89:2B:DA:0B:CB";
 const CONTROL_SERVER_UUID: &str = "0000dad0-0000-0000-0000-000000000000";
-const LEFT_RIGHT_UUID: &str = "0000dad0-0000-0000-0000-000000000001";
-const UP_DOWN_UUID: &str = "0000dad0-0000-0000-0000-000000000002";
-const SHOOT_UUID: &str = "0000dad0-0000-0000-0000-000000000003";
-const JUMP_UUID: &str = "0000dad0-0000-0000-0000-000000000004";
-const SPIN_UUID: &str = "0000dad0-0000-0000-0000-000000000005";
+const STATE_UUID: &str = "0000dad0-0000-0000-0000-000000000007";
+// State layout, see Control::pack in thingy-control/src/main.rs
+const STATE_LEN: usize = 8;
+const DEVICE_INFORMATION_SERVICE_UUID: &str = "0000180a-0000-1000-8000-00805f9b34fb";
+const DEVICE_INFORMATION_UUIDS: [(&str, &str); 6] = [
+    ("manufacturer", "00002a29-0000-1000-8000-00805f9b34fb"),
+    ("model", "00002a24-0000-1000-8000-00805f9b34fb"),
+    ("serial number", "00002a25-0000-1000-8000-00805f9b34fb"),
+    ("hardware revision", "00002a27-0000-1000-8000-00805f9b34fb"),
+    ("firmware revision", "00002a26-0000-1000-8000-00805f9b34fb"),
+    ("git hash", "00002a28-0000-1000-8000-00805f9b34fb"),
+];
+const BATTERY_SERVICE_UUID: &str = "0000180f-0000-1000-8000-00805f9b34fb";
+const BATTERY_LEVEL_UUID: &str = "00002a19-0000-1000-8000-00805f9b34fb";
+const DIAGNOSTICS_SERVICE_UUID: &str = "0000dad7-0000-0000-0000-000000000000";
+const CRASH_REPORT_UUID: &str = "0000dad7-0000-0000-0000-000000000003";
+const CRASH_ACK_UUID: &str = "0000dad7-0000-0000-0000-000000000005";
+
+// Crash report layout, see thingy-control/src/crash.rs
+const CRASH_FILE_LEN: usize = 32;
+const CRASH_MESSAGE_LEN: usize = 96;
+const CRASH_REPORT_LEN: usize = 25 + CRASH_FILE_LEN + CRASH_MESSAGE_LEN;
+
+// Battery percentage below which a warning is logged, override with BATTERY_WARN_LEVEL
+const DEFAULT_BATTERY_WARN_LEVEL: u8 = 20;
 
 static CONTROL_STATE: Lazy<Arc<Mutex<Control>>> =
     Lazy::new(|| Arc::new(Mutex::new(Control::default())));
 
+// Last State sequence number, to detect lost notifications
+static LAST_SEQUENCE: Lazy<Mutex<Option<u16>>> = Lazy::new(|| Mutex::new(None));
+
+static BATTERY_WARN_LEVEL: Lazy<u8> = Lazy::new(|| {
+    std::env::var("BATTERY_WARN_LEVEL")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(DEFAULT_BATTERY_WARN_LEVEL)
+});
+
 async fn create_consumer(
     channel: &Channel,
+    service_uuid: &str,
     characterist_uuid: &str,
 ) -> Result<Consumer, lapin::Error> {
-    let queue_name = format!("{DEVICE_ID}/{CONTROL_SERVER_UUID}/{characterist_uuid}");
+    let queue_name = format!("{DEVICE_ID}/{service_uuid}/{characterist_uuid}");
     channel
         .basic_consume(
             queue_name.as_str(),
@@ -95,7 +163,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let channel = connection.create_channel().await.unwrap();
 
     // Read queue and update CONTROL_STATE
-    create_consumer(&channel, LEFT_RIGHT_UUID)
+    create_consumer(&channel, CONTROL_SERVER_UUID, STATE_UUID)
         .await
         .map(|consumer| {
             consumer.set_delegate(move |delivery: DeliveryResult| async {
@@ -104,11 +172,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Ok(Some(delivery)) => delivery,
                 };
 
-                {
-                    let value: i8 = delivery.data[0] as i8;
-                    let mut control = CONTROL_STATE.lock().unwrap();
-                    control.left_right = value.into();
-                    debug!("RECEIVE left_right: {:?}", control.left_right);
+                // A malformed state is acknowledged and dropped, the next one replaces it
+                if let Some((control, sequence, timestamp)) = state(&delivery.data) {
+                    let mut last_sequence = LAST_SEQUENCE.lock().unwrap();
+                    if let Some(last) = *last_sequence {
+                        if sequence != last.wrapping_add(1) {
+                            warn!("state sequence gap: {} to {}", last, sequence);
+                        }
+                    }
+                    *last_sequence = Some(sequence);
+
+                    // The whole state is replaced at once, so the keys never see a partial change
+                    let mut current = CONTROL_STATE.lock().unwrap();
+                    *current = control;
+                    debug!(
+                        "RECEIVE state #{sequence} at {timestamp} ms: {:?}",
+                        *current
+                    );
                 }
 
                 delivery
@@ -118,7 +198,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             });
         })?;
 
-    create_consumer(&channel, UP_DOWN_UUID)
+    // Device information is read by the gateway when the controller connects
+    for (name, characterist_uuid) in DEVICE_INFORMATION_UUIDS {
+        create_consumer(&channel, DEVICE_INFORMATION_SERVICE_UUID, characterist_uuid)
+            .await
+            .map(|consumer| {
+                consumer.set_delegate(move |delivery: DeliveryResult| async move {
+                    let delivery = match delivery {
+                        Err(_) | Ok(None) => return,
+                        Ok(Some(delivery)) => delivery,
+                    };
+
+                    let value = String::from_utf8_lossy(&delivery.data);
+                    info!("{DEVICE_ID} {name}: {value}");
+
+                    delivery
+                        .ack(BasicAckOptions::default())
+                        .await
+                        .expect("Failed to ack send_webhook_event message");
+                });
+            })?;
+    }
+
+    // Battery level is only logged, it doesn't change the control state
+    create_consumer(&channel, BATTERY_SERVICE_UUID, BATTERY_LEVEL_UUID)
         .await
         .map(|consumer| {
             consumer.set_delegate(move |delivery: DeliveryResult| async {
@@ -127,11 +230,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Ok(Some(delivery)) => delivery,
                 };
 
-                {
-                    let value: i8 = delivery.data[0] as i8;
-                    let mut control = CONTROL_STATE.lock().unwrap();
-                    control.up_down = value.into();
-                    debug!("RECEIVE up_down: {:?}", control.up_down);
+                let level = delivery.data[0];
+                if level < *BATTERY_WARN_LEVEL {
+                    warn!("battery low: {}%", level);
+                } else {
+                    info!("battery: {}%", level);
                 }
 
                 delivery
@@ -141,71 +244,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             });
         })?;
 
-    create_consumer(&channel, SHOOT_UUID)
+    // The crash report is read by the gateway when the controller connects,
+    // it tells what reset the controller when that was a panic or a hard fault.
+    // A logged report is acknowledged, the gateway writes it to the controller.
+    let ack_channel = channel.clone();
+    create_consumer(&channel, DIAGNOSTICS_SERVICE_UUID, CRASH_REPORT_UUID)
         .await
         .map(|consumer| {
-            consumer.set_delegate(move |delivery: DeliveryResult| async {
-                let delivery = match delivery {
-                    Err(_) | Ok(None) => return,
-                    Ok(Some(delivery)) => delivery,
-                };
-
-                {
-                    let value = delivery.data[0] != 0;
-                    let mut control = CONTROL_STATE.lock().unwrap();
-                    control.shoot = value;
-                    debug!("RECEIVE shoot: {:?}", control.shoot);
+            consumer.set_delegate(move |delivery: DeliveryResult| {
+                let channel = ack_channel.clone();
+                async move {
+                    let delivery = match delivery {
+                        Err(_) | Ok(None) => return,
+                        Ok(Some(delivery)) => delivery,
+                    };
+
+                    match crash_report(&delivery.data) {
+                        Some(report) => {
+                            warn!("{DEVICE_ID} reported a crash: {report}");
+                            let queue_name =
+                                format!("{DEVICE_ID}/{DIAGNOSTICS_SERVICE_UUID}/{CRASH_ACK_UUID}");
+                            let published = channel
+                                .basic_publish(
+                                    "",
+                                    queue_name.as_str(),
+                                    BasicPublishOptions::default(),
+                                    &[1],
+                                    BasicProperties::default(),
+                                )
+                                .await;
+                            if let Err(e) = published {
+                                warn!("could not acknowledge the crash report: {e}");
+                            }
+                        }
+                        None => info!("{DEVICE_ID} has no crash to report"),
+                    }
+
+                    delivery
+                        .ack(BasicAckOptions::default())
+                        .await
+                        .expect("Failed to ack send_webhook_event message");
                 }
-
-                delivery
-                    .ack(BasicAckOptions::default())
-                    .await
-                    .expect("Failed to ack send_webhook_event message");
             });
         })?;
 
-    create_consumer(&channel, JUMP_UUID).await.map(|consumer| {
-        consumer.set_delegate(move |delivery: DeliveryResult| async {
-            let delivery = match delivery {
-                Err(_) | Ok(None) => return,
-                Ok(Some(delivery)) => delivery,
-            };
-
-            {
-                let value = delivery.data[0] != 0;
-                let mut control = CONTROL_STATE.lock().unwrap();
-                control.jump = value;
-                debug!("RECEIVE jump: {:?}", control.jump);
-            }
-
-            delivery
-                .ack(BasicAckOptions::default())
-                .await
-                .expect("Failed to ack send_webhook_event message");
-        });
-    })?;
-
-    create_consumer(&channel, SPIN_UUID).await.map(|consumer| {
-        consumer.set_delegate(move |delivery: DeliveryResult| async {
-            let delivery = match delivery {
-                Err(_) | Ok(None) => return,
-                Ok(Some(delivery)) => delivery,
-            };
-
-            {
-                let value = delivery.data[0] != 0;
-                let mut control = CONTROL_STATE.lock().unwrap();
-                control.spin = value;
-                debug!("RECEIVE spin: {:?}", control.spin);
-            }
-
-            delivery
-                .ack(BasicAckOptions::default())
-                .await
-                .expect("Failed to ack send_webhook_event message");
-        });
-    })?;
-
     // Dispach Keyboard events
     tokio::spawn(async move {
         let mut previous_control = Control::default();
@@ -218,6 +300,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             KeyCode::KEY_X,
             KeyCode::KEY_Z,
             KeyCode::KEY_C,
+            KeyCode::KEY_Q,
+            KeyCode::KEY_E,
+            KeyCode::KEY_V,
+            KeyCode::KEY_B,
         ] {
             keys_set.insert(key);
         }
@@ -306,6 +392,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
 
+            if previous_control.turn != current_control.turn {
+                info!(
+                    "turn: {:?} to {:?}",
+                    previous_control.turn, current_control.turn
+                );
+                match previous_control.turn {
+                    LeftRight::Left => keys_events.push(KeyCode::KEY_Q.release()),
+                    LeftRight::Right => keys_events.push(KeyCode::KEY_E.release()),
+                    LeftRight::None => {}
+                }
+
+                match current_control.turn {
+                    LeftRight::Left => keys_events.push(KeyCode::KEY_Q.press()),
+                    LeftRight::Right => keys_events.push(KeyCode::KEY_E.press()),
+                    LeftRight::None => {}
+                }
+            }
+
+            if previous_control.double_click != current_control.double_click {
+                info!(
+                    "double_click: {:?} to {:?}",
+                    previous_control.double_click, current_control.double_click
+                );
+                if current_control.double_click {
+                    keys_events.push(KeyCode::KEY_V.press());
+                } else {
+                    keys_events.push(KeyCode::KEY_V.release());
+                }
+            }
+
+            if previous_control.long_press != current_control.long_press {
+                info!(
+                    "long_press: {:?} to {:?}",
+                    previous_control.long_press, current_control.long_press
+                );
+                if current_control.long_press {
+                    keys_events.push(KeyCode::KEY_B.press());
+                } else {
+                    keys_events.push(KeyCode::KEY_B.release());
+                }
+            }
+
             previous_control = current_control;
             device.emit(&keys_events[..]).unwrap();
             tokio::time::sleep(std::time::Duration::from_millis(1)).await;
@@ -317,6 +445,54 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// Little endian [bits u16, sequence u16, timestamp u32], see the Control bitfield
+fn state(data: &[u8]) -> Option<(Control, u16, u32)> {
+    if data.len() != STATE_LEN {
+        warn!("invalid state length {}", data.len());
+        return None;
+    }
+    let bits = u16::from_le_bytes([data[0], data[1]]);
+    let sequence = u16::from_le_bytes([data[2], data[3]]);
+    let timestamp = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    match Control::try_from(bits) {
+        Ok(control) => Some((control, sequence, timestamp)),
+        Err(e) => {
+            warn!("state #{sequence}: {e} in {bits:#06x}");
+            None
+        }
+    }
+}
+
+// Little endian [kind u8, line u32, pc u32, lr u32, sp u32, cfsr u32, hfsr u32,
+// file [u8; 32], message [u8; 96]], None when the last reset wasn't a crash
+fn crash_report(data: &[u8]) -> Option<String> {
+    if data.len() != CRASH_REPORT_LEN {
+        warn!("invalid crash report length {}", data.len());
+        return None;
+    }
+    let kind = match data[0] {
+        1 => "panic",
+        2 => "hard fault",
+        _ => return None,
+    };
+    let word = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+    let text = |bytes: &[u8]| {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..len]).into_owned()
+    };
+    let file = text(&data[25..25 + CRASH_FILE_LEN]);
+    let message = text(&data[25 + CRASH_FILE_LEN..]);
+    Some(format!(
+        "{kind} at {file}:{} {message:?} (pc {:#010x}, lr {:#010x}, sp {:#010x}, cfsr {:#010x}, hfsr {:#010x})",
+        word(1),
+        word(5),
+        word(9),
+        word(13),
+        word(17),
+        word(21)
+    ))
+}
+
 trait KeyCodeExtension {
     fn release(&self) -> InputEvent;
     fn press(&self) -> InputEvent;
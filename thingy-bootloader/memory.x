@@ -0,0 +1,29 @@
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* Same partitions as thingy-control/memory.x, the MBR and the softdevice (S132 7.3.0) take the first 152K */
+  ACTIVE : ORIGIN = 152K, LENGTH = 156K
+  DFU : ORIGIN = 152K + 156K, LENGTH = 160K
+  BOOTLOADER_STATE : ORIGIN = 152K + 156K + 160K, LENGTH = 4K
+  /* The bootloader itself, up to the bonds page */
+  FLASH : ORIGIN = 152K + 156K + 160K + 4K, LENGTH = 28K
+  /* The MBR keeps its first 8 bytes, the firmware's crash report the last 256 */
+  RAM : ORIGIN = 0x20000008, LENGTH = 64K - 8 - 256
+  /* The MBR starts the bootloader at this address */
+  UICR_BOOTLOADER_ADDRESS : ORIGIN = 0x10001014, LENGTH = 4
+}
+
+__bootloader_active_start = ORIGIN(ACTIVE);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE);
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
+
+SECTIONS
+{
+  .uicr_bootloader_address :
+  {
+    LONG(ORIGIN(FLASH));
+  } > UICR_BOOTLOADER_ADDRESS
+}
@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_nrf::{BootLoader, BootLoaderConfig, WatchdogFlash};
+use embassy_nrf::nvmc::Nvmc;
+use embassy_nrf::wdt;
+use embassy_sync::blocking_mutex::Mutex;
+use embedded_storage::nor_flash::NorFlash;
+
+// based on: https://github.com/embassy-rs/embassy/blob/main/examples/boot/bootloader/nrf/src/main.rs
+// Swaps in a verified update (or back to the previous firmware when the update
+// wasn't marked booted), then starts the softdevice which starts the firmware.
+#[entry]
+fn main() -> ! {
+    let p = embassy_nrf::init(Default::default());
+    let flash = Nvmc::new(p.NVMC);
+
+    // The firmware's watchdog survives its soft reset into an update, so it's fed
+    // while swapping, as configured. It isn't started otherwise: once started it
+    // couldn't be stopped for the firmware's boot. A swap interrupted by a reset
+    // is resumed on the next boot.
+    match wdt::Config::try_new(&p.WDT) {
+        Some(config) => boot(WatchdogFlash::start(flash, p.WDT, config)),
+        None => boot(flash),
+    }
+}
+
+fn boot<F: NorFlash>(flash: F) -> ! {
+    let flash = Mutex::new(RefCell::new(flash));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash);
+    let active_offset = config.active.offset();
+    let bootloader: BootLoader = BootLoader::prepare(config);
+
+    unsafe { bootloader.load(active_offset) }
+}
+
+#[no_mangle]
+#[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    const SCB_ICSR: *const u32 = 0xE000_ED04 as *const u32;
+    let irqn = core::ptr::read_volatile(SCB_ICSR) as u8 as i16 - 16;
+
+    panic!("DefaultHandler #{:?}", irqn);
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::asm::udf();
+}
@@ -0,0 +1,18 @@
+// Host tests of the thingy-control modules that don't touch the hardware.
+// The firmware only builds for the nRF52, so its modules are included here as
+// they are and `cargo test` runs the tests they carry on the computer.
+#[allow(dead_code)]
+#[path = "../../thingy-control/src/advertisement.rs"]
+mod advertisement;
+#[allow(dead_code)]
+#[path = "../../thingy-control/src/control.rs"]
+mod control;
+#[allow(dead_code, clippy::wrong_self_convention)]
+#[path = "../../thingy-control/src/fusion.rs"]
+mod fusion;
+#[allow(dead_code)]
+#[path = "../../thingy-control/src/gesture.rs"]
+mod gesture;
+
+// Where the firmware's modules find them, at the crate root
+use control::{Control, LeftRight, UpDown};
@@ -12,6 +12,7 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -29,6 +30,50 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // Expose the commit hash to the firmware as `env!("GIT_HASH")`,
+    // so builds can be told apart over the air.
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+
+    // Public key the firmware updates must be signed with, made by
+    // `dfu-tool keygen`. There's no default, any stand-in key would be trusted.
+    let key_path = env::var("DFU_PUBLIC_KEY").unwrap_or_else(|_| "dfu_key.pub".to_owned());
+    let public_key = std::fs::read(&key_path).unwrap_or_else(|e| {
+        panic!("could not read {key_path} ({e}), make one with `dfu-tool keygen` or set DFU_PUBLIC_KEY")
+    });
+    assert_eq!(
+        public_key.len(),
+        32,
+        "{key_path} must be an ed25519 public key"
+    );
+    File::create(out.join("dfu_public_key.bin"))
+        .unwrap()
+        .write_all(&public_key)
+        .unwrap();
+    println!("cargo:rerun-if-changed={}", key_path);
+    println!("cargo:rerun-if-env-changed=DFU_PUBLIC_KEY");
+
+    // Gesture trees made by `gesture-trainer`, without them the firmware
+    // keeps the hand written decision tree and its configurable thresholds.
+    let model_path = env::var("GESTURE_MODEL").unwrap_or_else(|_| "gesture_model.rs".to_owned());
+    let model = std::fs::read_to_string(&model_path)
+        .unwrap_or_else(|_| "pub const MODEL: Option<Model> = None;\n".to_owned());
+    File::create(out.join("gesture_model.rs"))
+        .unwrap()
+        .write_all(model.as_bytes())
+        .unwrap();
+    println!("cargo:rerun-if-changed={}", model_path);
+    println!("cargo:rerun-if-env-changed=GESTURE_MODEL");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
@@ -1,2 +1,5 @@
   probe-rs erase --chip nrf52832_xxAA
   probe-rs download --chip nrf52832_xxAA --format hex s132_nrf52_7.3.0_softdevice.hex
+  # Bootloader for the firmware updates over BLE, see ../thingy-bootloader
+  (cd ../thingy-bootloader && cargo build --release)
+  probe-rs download --chip nrf52832_xxAA ../thingy-bootloader/target/thumbv7em-none-eabihf/release/thingy-bootloader
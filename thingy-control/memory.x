@@ -2,6 +2,34 @@ MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* These values correspond to the NRF52832 with SoftDevices S132 7.3.0 */
-  FLASH : ORIGIN = 0x00000000 + 152K, LENGTH = 512K - 152K
-  RAM : ORIGIN = 0x2000d478, LENGTH = 64K - 0xd478
+  /* The firmware runs from ACTIVE, right after the softdevice */
+  FLASH : ORIGIN = 0x00000000 + 152K, LENGTH = 156K
+  /* Firmware updates are received here, one page larger to swap with ACTIVE */
+  DFU : ORIGIN = 152K + 156K, LENGTH = 160K
+  /* Tells the bootloader (thingy-bootloader, right after it) to swap */
+  BOOTLOADER_STATE : ORIGIN = 152K + 156K + 160K, LENGTH = 4K
+  /* Bonded peers keys and system attributes */
+  BONDS : ORIGIN = 512K - 12K, LENGTH = 4K
+  /* Second to last page keeps the IMU calibration */
+  CALIBRATION : ORIGIN = 512K - 8K, LENGTH = 4K
+  /* Last page keeps the runtime configuration */
+  CONFIG : ORIGIN = 512K - 4K, LENGTH = 4K
+  /* Starts where the softdevice RAM ends for the config of ble::softdevice_setup,
+     Softdevice::enable logs the address it needs. From one link: 16 bytes per UUID
+     base, 0xa80 for the larger GATT table and 0x1400 for the second link's buffers */
+  RAM : ORIGIN = 0x2000f308, LENGTH = 64K - 0xf308 - 256
+  /* The last crash report, kept across resets as long as the power stays */
+  CRASH : ORIGIN = 0x20000000 + 64K - 256, LENGTH = 256
 }
+
+__bonds_start = ORIGIN(BONDS);
+__calibration_start = ORIGIN(CALIBRATION);
+__config_start = ORIGIN(CONFIG);
+__crash_start = ORIGIN(CRASH);
+
+__bootloader_active_start = ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(FLASH) + LENGTH(FLASH);
+__bootloader_dfu_start = ORIGIN(DFU);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU);
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE);
@@ -0,0 +1,143 @@
+use defmt::Format;
+
+// Legacy advertising and scan response payloads are limited to 31 bytes
+const ADV_DATA_MAX_LEN: usize = 31;
+
+// https://www.bluetooth.com/specifications/assigned-numbers/ (Common Data Types)
+const AD_FLAGS: u8 = 0x01;
+const AD_COMPLETE_UUID_128: u8 = 0x07;
+const AD_SHORTENED_NAME: u8 = 0x08;
+const AD_COMPLETE_NAME: u8 = 0x09;
+const AD_TX_POWER: u8 = 0x0a;
+const AD_APPEARANCE: u8 = 0x19;
+
+// A structure didn't fit in the remaining space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TooLong;
+
+// Length, type and value structures of an advertising or scan response payload
+pub struct AdvertisementData {
+    buf: [u8; ADV_DATA_MAX_LEN],
+    len: usize,
+}
+
+impl AdvertisementData {
+    pub const fn new() -> Self {
+        AdvertisementData {
+            buf: [0; ADV_DATA_MAX_LEN],
+            len: 0,
+        }
+    }
+
+    fn free(&self) -> usize {
+        ADV_DATA_MAX_LEN - self.len
+    }
+
+    fn push(mut self, ad_type: u8, value: &[u8]) -> Result<Self, TooLong> {
+        if value.len() + 2 > self.free() {
+            return Err(TooLong);
+        }
+        self.buf[self.len] = value.len() as u8 + 1;
+        self.buf[self.len + 1] = ad_type;
+        self.buf[self.len + 2..self.len + 2 + value.len()].copy_from_slice(value);
+        self.len += value.len() + 2;
+        Ok(self)
+    }
+
+    pub fn flags(self, flags: u8) -> Result<Self, TooLong> {
+        self.push(AD_FLAGS, &[flags])
+    }
+
+    pub fn full_uuid_128(self, uuid: &[u8; 16]) -> Result<Self, TooLong> {
+        self.push(AD_COMPLETE_UUID_128, uuid)
+    }
+
+    pub fn appearance(self, appearance: u16) -> Result<Self, TooLong> {
+        self.push(AD_APPEARANCE, &appearance.to_le_bytes())
+    }
+
+    pub fn tx_power(self, dbm: i8) -> Result<Self, TooLong> {
+        self.push(AD_TX_POWER, &dbm.to_le_bytes())
+    }
+
+    // Names too long for the remaining space are shortened, down to one character
+    pub fn name(self, name: &[u8]) -> Result<Self, TooLong> {
+        let room = self.free().saturating_sub(2);
+        if name.len() <= room {
+            self.push(AD_COMPLETE_NAME, name)
+        } else if room > 0 {
+            self.push(AD_SHORTENED_NAME, &name[..room])
+        } else {
+            Err(TooLong)
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_each_structure() {
+        let uuid: [u8; 16] = core::array::from_fn(|i| i as u8);
+        let data = AdvertisementData::new()
+            .flags(0x06)
+            .and_then(|d| d.full_uuid_128(&uuid))
+            .and_then(|d| d.appearance(0x03c4))
+            .and_then(|d| d.tx_power(-4))
+            .unwrap();
+
+        let mut expected = vec![0x02, 0x01, 0x06, 0x11, 0x07];
+        expected.extend_from_slice(&uuid);
+        expected.extend_from_slice(&[0x03, 0x19, 0xc4, 0x03, 0x02, 0x0a, 0xfc]);
+        assert_eq!(data.as_bytes(), expected.as_slice());
+        assert_eq!(data.as_bytes().len(), 28);
+    }
+
+    #[test]
+    fn a_name_that_fits_is_complete() {
+        let data = AdvertisementData::new().name(b"Thingy").unwrap();
+        assert_eq!(data.as_bytes(), b"\x07\x09Thingy");
+    }
+
+    #[test]
+    fn a_long_name_is_shortened_to_31_bytes() {
+        let name = b"Thingy Wii Control with a much longer name";
+        let data = AdvertisementData::new().name(name).unwrap();
+        let bytes = data.as_bytes();
+        assert_eq!(bytes.len(), 31);
+        assert_eq!(bytes[..2], [30, 0x08]);
+        assert_eq!(bytes[2..], name[..29]);
+    }
+
+    #[test]
+    fn a_name_exactly_filling_the_payload_is_complete() {
+        let name = [b'a'; 29];
+        let data = AdvertisementData::new().name(&name).unwrap();
+        assert_eq!(data.as_bytes().len(), 31);
+        assert_eq!(data.as_bytes()[..2], [30, 0x09]);
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let uuid = [0u8; 16];
+        let data = AdvertisementData::new()
+            .full_uuid_128(&uuid)
+            .and_then(|d| d.full_uuid_128(&uuid));
+        assert_eq!(data.err(), Some(TooLong));
+
+        // 29 bytes used, 2 left: no room for a name character
+        let data = AdvertisementData::new()
+            .full_uuid_128(&uuid)
+            .and_then(|d| d.appearance(0))
+            .and_then(|d| d.appearance(0))
+            .and_then(|d| d.tx_power(0))
+            .unwrap();
+        assert_eq!(data.as_bytes().len(), 29);
+        assert_eq!(data.name(b"T").err(), Some(TooLong));
+    }
+}
@@ -0,0 +1,52 @@
+use core::f32::consts::{FRAC_PI_4, PI};
+
+use defmt::Format;
+use libm::{copysignf, fabsf, powf};
+
+use crate::config::Settings;
+
+// Tilt and rotation speed mapped to the full axis range
+const TILT_FULL_SCALE: f32 = FRAC_PI_4; // rad
+const YAW_RATE_FULL_SCALE: f32 = 2.0 * PI; // rad/s
+
+// Proportional axes, same signs as the tri-state controls:
+// pitch > 0 is down, roll > 0 is left and yaw_rate > 0 is spin.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Format)]
+pub struct AnalogAxes {
+    pub pitch: i16,
+    pub roll: i16,
+    pub yaw_rate: i16,
+}
+
+impl AnalogAxes {
+    pub fn new(pitch: f32, roll: f32, yaw_rate: f32, settings: &Settings) -> Self {
+        let shape = |value, full_scale| shape(value, full_scale, settings.deadzone, settings.curve);
+        AnalogAxes {
+            pitch: shape(pitch, TILT_FULL_SCALE),
+            roll: shape(roll, TILT_FULL_SCALE),
+            yaw_rate: shape(yaw_rate, YAW_RATE_FULL_SCALE),
+        }
+    }
+
+    // Little endian [pitch, roll, yaw_rate]
+    pub fn to_bytes(self) -> [u8; 6] {
+        let mut buf = [0u8; 6];
+        buf[0..2].copy_from_slice(&self.pitch.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.roll.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.yaw_rate.to_le_bytes());
+        buf
+    }
+}
+
+// Normalize to [-1, 1], cut the deadzone and rescale what is left,
+// then apply the response curve (exponent > 1 gives finer control near the center)
+fn shape(value: f32, full_scale: f32, deadzone: f32, curve: f32) -> i16 {
+    let normalized = (value / full_scale).clamp(-1.0, 1.0);
+    let magnitude = fabsf(normalized);
+    if magnitude <= deadzone {
+        return 0;
+    }
+
+    let magnitude = powf((magnitude - deadzone) / (1.0 - deadzone), curve);
+    (copysignf(magnitude, normalized) * i16::MAX as f32) as i16
+}
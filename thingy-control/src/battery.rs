@@ -0,0 +1,103 @@
+use defmt::*;
+use embassy_nrf::saadc::Saadc;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+use crate::ble::Connections;
+use crate::speaker::Sound;
+use crate::{unwrap_notify, Server};
+
+const MEASUREMENT_INTERVAL: Duration = Duration::from_secs(10);
+pub const LOW_BATTERY_LEVEL: u8 = 10; // percent
+
+// Thingy:52 battery monitor: VBAT -- 1.5M -- AIN4 -- 180k -- GND
+const DIVIDER_R1: u32 = 1_500_000;
+const DIVIDER_R2: u32 = 180_000;
+
+// Internal 0.6V reference with 1/2 gain, 12 bits resolution
+const ADC_FULL_SCALE_MV: u32 = 1200;
+const ADC_RESOLUTION: u32 = 4096;
+
+// LiPo discharge curve as (millivolts, percentage), in descending voltage
+const DISCHARGE_CURVE: [(u32, u8); 11] = [
+    (4200, 100),
+    (4100, 90),
+    (4000, 78),
+    (3900, 65),
+    (3800, 50),
+    (3750, 40),
+    (3700, 30),
+    (3650, 20),
+    (3600, 12),
+    (3500, 5),
+    (3300, 0),
+];
+
+// GATT Service
+#[nrf_softdevice::gatt_service(uuid = "180f")]
+pub struct BatteryService {
+    #[characteristic(uuid = "2a19", read, notify)]
+    battery_level: u8, // 0 to 100 percent
+}
+
+fn raw_to_millivolts(raw: i16) -> u32 {
+    let adc_mv = (raw.max(0) as u32) * ADC_FULL_SCALE_MV / ADC_RESOLUTION;
+    adc_mv * (DIVIDER_R1 + DIVIDER_R2) / DIVIDER_R2
+}
+
+// Linear interpolation between the discharge curve points
+fn millivolts_to_percentage(mv: u32) -> u8 {
+    let (max_mv, max_level) = DISCHARGE_CURVE[0];
+    if mv >= max_mv {
+        return max_level;
+    }
+
+    for window in DISCHARGE_CURVE.windows(2) {
+        let (high_mv, high_level) = window[0];
+        let (low_mv, low_level) = window[1];
+        if mv >= low_mv {
+            let level_range = (high_level - low_level) as u32;
+            return low_level + ((mv - low_mv) * level_range / (high_mv - low_mv)) as u8;
+        }
+    }
+
+    0
+}
+
+// Measure the battery periodically, update the characteristic and notify changes.
+// Beeps once when the level drops below LOW_BATTERY_LEVEL.
+pub async fn battery_task<'a>(
+    saadc: &mut Saadc<'static, 1>,
+    server: &'a Server,
+    connections: &'a Connections,
+    sounds: &'a Signal<NoopRawMutex, Sound>,
+) {
+    let mut previous_level = None;
+    loop {
+        let mut buf = [0; 1];
+        saadc.sample(&mut buf).await;
+
+        let mv = raw_to_millivolts(buf[0]);
+        let level = millivolts_to_percentage(mv);
+        debug!("battery: {} mV, {}%", mv, level);
+
+        if previous_level != Some(level) {
+            info!("battery_level: {}", level);
+            unwrap!(server.bas.battery_level_set(&level));
+            connections.for_each(|connection| {
+                unwrap_notify(
+                    server.bas.battery_level_notify(connection, &level),
+                    "battery_level",
+                )
+            });
+            let was_low = previous_level.is_some_and(|previous| previous < LOW_BATTERY_LEVEL);
+            if level < LOW_BATTERY_LEVEL && !was_low {
+                sounds.signal(Sound::LowBattery);
+            }
+            previous_level = Some(level);
+        }
+
+        Timer::after(MEASUREMENT_INTERVAL).await;
+    }
+}
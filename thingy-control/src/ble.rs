@@ -3,21 +3,106 @@ use embassy_executor::Spawner;
 use nrf_softdevice::ble::peripheral::AdvertiseError;
 use nrf_softdevice::ble::{peripheral, Connection};
 use nrf_softdevice::{raw, Softdevice};
+use static_cell::StaticCell;
 
+use embassy_nrf::pac;
+
+use core::cell::RefCell;
 use core::mem;
 
+use crate::advertisement::{AdvertisementData, TooLong};
+use crate::bond::Bonder;
+use crate::link;
 use crate::Server;
 
+// Every unit shares the prefix, the last 2 bytes of its address tell them apart
+const DEVICE_NAME_PREFIX: &[u8] = b"Thingy Wii Control ";
+pub const DEVICE_NAME_LEN: usize = DEVICE_NAME_PREFIX.len() + 4;
+
+// ControlService UUID 0000DAD0-0000-0000-0000-000000000000, little endian as sent over the air
+const CONTROL_SERVICE_UUID: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xd0, 0xda, 0, 0];
+// Assigned numbers: Generic HID, Gamepad
+const GAMEPAD_APPEARANCE: u16 = 0x03c4;
+// Softdevice default radio power
+const TX_POWER_DBM: i8 = 0;
+// The softdevice tells 128-bit UUID bases apart by all but bytes 12-13, so each
+// `0000DADx-0000-0000-0000-00000000000N` suffix is its own base, N from 0 to A
+const VS_UUID_COUNT: u8 = 11;
+// GATT table of the 11 services, their ~50 characteristics and the values the
+// softdevice keeps (the default 1408 bytes only fit a few services)
+const ATTR_TAB_SIZE: u32 = 4096;
+
+// FICR DEVICEADDR is the random static address the softdevice advertises with
+pub fn device_name() -> [u8; DEVICE_NAME_LEN] {
+    let ficr = unsafe { &*pac::FICR::ptr() };
+    let suffix = ficr.deviceaddr[0].read().bits() as u16;
+
+    let mut name = [0u8; DEVICE_NAME_LEN];
+    name[..DEVICE_NAME_PREFIX.len()].copy_from_slice(DEVICE_NAME_PREFIX);
+    for (i, digit) in name[DEVICE_NAME_PREFIX.len()..].iter_mut().enumerate() {
+        let nibble = (suffix >> (12 - 4 * i)) & 0xf;
+        *digit = b"0123456789ABCDEF"[nibble as usize];
+    }
+    name
+}
+
+// The security handler must outlive every connection
+static BONDER: StaticCell<Bonder> = StaticCell::new();
+
+// Centrals served at the same time, e.g. the gateway and a spectator dashboard
+pub const MAX_CONNECTIONS: usize = 2;
+
+// Connected centrals, the tasks notify all of them
+pub struct Connections {
+    slots: RefCell<[Option<Connection>; MAX_CONNECTIONS]>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Connections {
+            slots: RefCell::new(Default::default()),
+        }
+    }
+
+    pub fn insert(&self, slot: usize, connection: &Connection) {
+        self.slots.borrow_mut()[slot] = Some(connection.clone());
+    }
+
+    pub fn remove(&self, slot: usize) {
+        self.slots.borrow_mut()[slot] = None;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.borrow().iter().all(Option::is_none)
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&Connection)) {
+        for connection in self.slots.borrow().iter().flatten() {
+            f(connection);
+        }
+    }
+
+    // Same as for_each, along with the slot of each connection
+    pub fn for_each_slot(&self, mut f: impl FnMut(usize, &Connection)) {
+        for (slot, connection) in self.slots.borrow().iter().enumerate() {
+            if let Some(connection) = connection {
+                f(slot, connection);
+            }
+        }
+    }
+}
+
 #[embassy_executor::task]
 async fn softdevice_task(sd: &'static Softdevice) -> ! {
-    sd.run().await
+    sd.run_with_callback(link::on_event).await
 }
 
 // from: https://github.com/embassy-rs/nrf-softdevice/blob/487f98ea03638472fcd66ed16c5f9c97c501e876/examples/src/bin/ble_bas_peripheral_notify.rs#L106-L152
+// The bonder starts empty, the stored bonds are loaded once the flash is available.
 pub fn softdevice_setup<'a, const N: usize>(
     spawner: &'a Spawner,
     device_name: &[u8; N],
-) -> (&'a Softdevice, Server) {
+) -> (&'a Softdevice, Server, &'static Bonder) {
     let config = nrf_softdevice::Config {
         clock: Some(raw::nrf_clock_lf_cfg_t {
             source: raw::NRF_CLOCK_LF_SRC_RC as u8,
@@ -26,16 +111,19 @@ pub fn softdevice_setup<'a, const N: usize>(
             accuracy: raw::NRF_CLOCK_LF_ACCURACY_500_PPM as u8,
         }),
         conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 1,
+            conn_count: MAX_CONNECTIONS as u8,
             event_length: 24,
         }),
         conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 256 }),
+        common_vs_uuid: Some(raw::ble_common_cfg_vs_uuid_t {
+            vs_uuid_count: VS_UUID_COUNT,
+        }),
         gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
-            attr_tab_size: raw::BLE_GATTS_ATTR_TAB_SIZE_DEFAULT.into(),
+            attr_tab_size: ATTR_TAB_SIZE,
         }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: raw::BLE_GAP_ADV_SET_COUNT_DEFAULT as u8,
-            periph_role_count: raw::BLE_GAP_ROLE_COUNT_PERIPH_DEFAULT as u8,
+            periph_role_count: MAX_CONNECTIONS as u8,
             central_role_count: 0,
             central_sec_count: 0,
             _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
@@ -53,43 +141,55 @@ pub fn softdevice_setup<'a, const N: usize>(
     };
 
     let sd = Softdevice::enable(&config);
+    // Shown by HID hosts before connecting, same as the advertised one
+    let ret = unsafe { raw::sd_ble_gap_appearance_set(GAMEPAD_APPEARANCE) };
+    if ret != raw::NRF_SUCCESS {
+        warn!("could not set appearance: {}", ret);
+    }
     let server = unwrap!(Server::new(sd));
+    let bonder = BONDER.init(Bonder::new());
 
     unwrap!(spawner.spawn(softdevice_task(sd)));
 
-    return (sd, server);
+    return (sd, server, bonder);
+}
+
+#[derive(Debug, Format)]
+pub enum AdvertisingError {
+    TooLong, // the advertising data doesn't fit in 31 bytes
+    Advertise(AdvertiseError),
+}
+
+impl From<TooLong> for AdvertisingError {
+    fn from(_: TooLong) -> Self {
+        AdvertisingError::TooLong
+    }
 }
 
-// based on: https://github.com/embassy-rs/nrf-softdevice/blob/487f98ea03638472fcd66ed16c5f9c97c501e876/examples/src/bin/ble_bas_peripheral_notify.rs#L143-L152
-// but uses the incredible const generics to make the device name length a compile-time constant
-pub async fn advertise_connectable<const N: usize>(
+impl From<AdvertiseError> for AdvertisingError {
+    fn from(e: AdvertiseError) -> Self {
+        AdvertisingError::Advertise(e)
+    }
+}
+
+// Centrals can pair "just works" with LE Secure Connections and bond through `bonder`.
+// The advertisement lets the gateway filter by service, the name goes in the scan response.
+pub async fn advertise_connectable(
     sd: &Softdevice,
-    device_name: &[u8; N],
-) -> Result<Connection, AdvertiseError>
-where
-    [(); N + 9]:,
-{
-    let adv_data = &mut [0; N + 9];
-
-    // https://docs.silabs.com/bluetooth/4.0/general/adv-and-scanning/bluetooth-adv-data-basics
-    adv_data[..9].copy_from_slice(&[
-        0x02,
-        0x01,
-        raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
-        0x03,
-        0x03,
-        0x09,
-        0x18,
-        (device_name.len() + 1) as u8,
-        0x09,
-    ]);
-    adv_data[9..].copy_from_slice(device_name);
+    device_name: &[u8],
+    bonder: &'static Bonder,
+) -> Result<Connection, AdvertisingError> {
+    let adv_data = AdvertisementData::new()
+        .flags(raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8)?
+        .full_uuid_128(&CONTROL_SERVICE_UUID)?
+        .appearance(GAMEPAD_APPEARANCE)?
+        .tx_power(TX_POWER_DBM)?;
+    let scan_data = AdvertisementData::new().name(device_name)?;
 
     let config = peripheral::Config::default();
-    let scan_data = &[0x03, 0x03, 0x09, 0x18];
     let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
-        adv_data,
-        scan_data,
+        adv_data: adv_data.as_bytes(),
+        scan_data: scan_data.as_bytes(),
     };
-    peripheral::advertise_connectable(sd, adv, &config).await
+    Ok(peripheral::advertise_pairable(sd, adv, &config, bonder).await?)
 }
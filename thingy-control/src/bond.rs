@@ -0,0 +1,246 @@
+use core::cell::RefCell;
+use core::mem::{size_of, transmute_copy};
+
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_softdevice::ble::gatt_server;
+use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
+use nrf_softdevice::ble::{Connection, EncryptionInfo, IdentityKey, MasterId};
+use nrf_softdevice::Flash;
+
+use crate::config::AlignedBuffer;
+
+// Flash page reserved for the bonds in memory.x
+extern "C" {
+    static __bonds_start: u32;
+}
+
+fn bonds_address() -> u32 {
+    unsafe { &__bonds_start as *const u32 as u32 }
+}
+
+// Marks a written bonds page, erased flash reads as 0xFFFFFFFF
+const BONDS_MAGIC: u32 = 0xB0AD_0001;
+pub const MAX_BONDS: usize = 4;
+// CCCD values of the whole server
+const SYS_ATTRS_SIZE: usize = 128;
+// The notified characteristics: 8 of control, then HID, battery, raw IMU, DFU,
+// calibration and diagnostics (no Service Changed, it's disabled by default)
+const CCCD_COUNT: usize = 14;
+// Each CCCD is a handle, a length and its value (2 bytes each), then a CRC
+const _: () = assert!(CCCD_COUNT * 6 + 2 <= SYS_ATTRS_SIZE);
+
+const PEER_SIZE: usize = size_of::<Peer>();
+// used flag, sys attrs length, peer keys and sys attrs, word aligned
+const ENTRY_SIZE: usize = (2 + PEER_SIZE + SYS_ATTRS_SIZE + 3) / 4 * 4;
+const BONDS_SIZE: usize = 4 + MAX_BONDS * ENTRY_SIZE;
+
+// Keys exchanged when bonding. The softdevice types are plain data,
+// so they are copied to flash as they are in memory.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Peer {
+    master_id: MasterId,
+    key: EncryptionInfo,
+    peer_id: IdentityKey,
+}
+
+impl Peer {
+    fn to_bytes(self) -> [u8; PEER_SIZE] {
+        unsafe { transmute_copy(&self) }
+    }
+
+    fn from_bytes(buf: &[u8]) -> Self {
+        let mut bytes = [0u8; PEER_SIZE];
+        bytes.copy_from_slice(buf);
+        unsafe { transmute_copy(&bytes) }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Bond {
+    peer: Peer,
+    sys_attrs: [u8; SYS_ATTRS_SIZE], // the peer CCCDs, so it keeps its subscriptions
+    sys_attrs_len: usize,
+}
+
+impl Bond {
+    fn is_peer(&self, connection: &Connection) -> bool {
+        self.peer.peer_id.is_match(connection.peer_address())
+    }
+}
+
+// "Just works" pairing (no display nor keyboard), bonded peers are kept
+// in RAM and stored to flash by the config task when `changed` is signaled
+pub struct Bonder {
+    bonds: RefCell<[Option<Bond>; MAX_BONDS]>,
+    pub changed: Signal<NoopRawMutex, ()>,
+}
+
+impl Bonder {
+    pub const fn new() -> Self {
+        Bonder {
+            bonds: RefCell::new([None; MAX_BONDS]),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn clear(&self) {
+        info!("clearing bonds");
+        *self.bonds.borrow_mut() = [None; MAX_BONDS];
+        self.changed.signal(());
+    }
+
+    fn to_bytes(&self) -> [u8; BONDS_SIZE] {
+        let mut buf = [0u8; BONDS_SIZE];
+        buf[0..4].copy_from_slice(&BONDS_MAGIC.to_le_bytes());
+        for (i, bond) in self.bonds.borrow().iter().enumerate() {
+            let Some(bond) = bond else {
+                continue;
+            };
+            let entry = &mut buf[4 + i * ENTRY_SIZE..4 + (i + 1) * ENTRY_SIZE];
+            entry[0] = 1;
+            entry[1] = bond.sys_attrs_len as u8;
+            entry[2..2 + PEER_SIZE].copy_from_slice(&bond.peer.to_bytes());
+            entry[2 + PEER_SIZE..2 + PEER_SIZE + SYS_ATTRS_SIZE].copy_from_slice(&bond.sys_attrs);
+        }
+        buf
+    }
+
+    fn load_bytes(&self, buf: &[u8; BONDS_SIZE]) {
+        if u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) != BONDS_MAGIC {
+            info!("no stored bonds");
+            return;
+        }
+
+        let mut bonds = self.bonds.borrow_mut();
+        for (i, bond) in bonds.iter_mut().enumerate() {
+            let entry = &buf[4 + i * ENTRY_SIZE..4 + (i + 1) * ENTRY_SIZE];
+            if entry[0] != 1 {
+                continue;
+            }
+            let mut sys_attrs = [0u8; SYS_ATTRS_SIZE];
+            sys_attrs.copy_from_slice(&entry[2 + PEER_SIZE..2 + PEER_SIZE + SYS_ATTRS_SIZE]);
+            *bond = Some(Bond {
+                peer: Peer::from_bytes(&entry[2..2 + PEER_SIZE]),
+                sys_attrs,
+                sys_attrs_len: (entry[1] as usize).min(SYS_ATTRS_SIZE),
+            });
+        }
+        info!("{} stored bonds", bonds.iter().flatten().count());
+    }
+
+    pub async fn load(&self, flash: &mut Flash) {
+        let mut buf = [0u8; BONDS_SIZE];
+        match flash.read(bonds_address(), &mut buf).await {
+            Ok(()) => self.load_bytes(&buf),
+            Err(e) => warn!("could not read bonds: {:?}", e),
+        }
+    }
+}
+
+impl SecurityHandler for Bonder {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::None
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        true
+    }
+
+    fn on_bonded(
+        &self,
+        conn: &Connection,
+        master_id: MasterId,
+        key: EncryptionInfo,
+        peer_id: IdentityKey,
+    ) {
+        info!("bonded");
+        let bond = Bond {
+            peer: Peer {
+                master_id,
+                key,
+                peer_id,
+            },
+            sys_attrs: [0; SYS_ATTRS_SIZE],
+            sys_attrs_len: 0,
+        };
+
+        // A known peer replaces its bond, otherwise the oldest one is dropped when full
+        let mut bonds = self.bonds.borrow_mut();
+        let known = bonds
+            .iter()
+            .position(|b| b.is_some_and(|b| b.is_peer(conn)));
+        let index = match known.or_else(|| bonds.iter().position(Option::is_none)) {
+            Some(index) => index,
+            None => {
+                bonds.rotate_left(1);
+                MAX_BONDS - 1
+            }
+        };
+        bonds[index] = Some(bond);
+        self.changed.signal(());
+    }
+
+    // LE Secure Connections bonds all have a zero EDIV and rand, so the peer address
+    // is matched first, the master id only tells legacy bonds apart
+    fn get_key(&self, conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        let bonds = self.bonds.borrow();
+        let by_address = bonds.iter().flatten().find(|b| b.is_peer(conn));
+        let by_master_id = || {
+            bonds
+                .iter()
+                .flatten()
+                .find(|b| b.peer.master_id == master_id)
+        };
+        by_address.or_else(by_master_id).map(|b| b.peer.key)
+    }
+
+    fn save_sys_attrs(&self, conn: &Connection) {
+        let mut bonds = self.bonds.borrow_mut();
+        let Some(bond) = bonds.iter_mut().flatten().find(|b| b.is_peer(conn)) else {
+            return;
+        };
+
+        match gatt_server::get_sys_attrs(conn, &mut bond.sys_attrs) {
+            Ok(len) => {
+                bond.sys_attrs_len = len;
+                self.changed.signal(());
+            }
+            Err(e) => warn!("could not get sys attrs: {:?}", e),
+        }
+    }
+
+    fn load_sys_attrs(&self, conn: &Connection) {
+        let bonds = self.bonds.borrow();
+        let sys_attrs = bonds
+            .iter()
+            .flatten()
+            .find(|b| b.is_peer(conn) && b.sys_attrs_len > 0)
+            .map(|b| &b.sys_attrs[..b.sys_attrs_len]);
+        if let Err(e) = gatt_server::set_sys_attrs(conn, sys_attrs) {
+            // Stale once a firmware update changed the GATT table, the peer subscribes again
+            warn!("could not set sys attrs: {:?}", e);
+            if let Err(e) = gatt_server::set_sys_attrs(conn, None) {
+                warn!("could not reset sys attrs: {:?}", e);
+            }
+        }
+    }
+}
+
+pub async fn store_bonds(flash: &mut Flash, bonder: &Bonder) {
+    let address = bonds_address();
+    if let Err(e) = flash
+        .erase(address, address + Flash::ERASE_SIZE as u32)
+        .await
+    {
+        warn!("could not erase bonds: {:?}", e);
+        return;
+    }
+    let buf = AlignedBuffer(bonder.to_bytes());
+    if let Err(e) = flash.write(address, &buf.0).await {
+        warn!("could not write bonds: {:?}", e);
+    }
+}
@@ -0,0 +1,142 @@
+use core::cell::{Cell, RefCell};
+
+use defmt::*;
+use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
+use embassy_nrf::pac;
+use embassy_nrf::peripherals::TWISPI0;
+use embassy_nrf::twim::Twim;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::NoopMutex;
+use embassy_time::{block_for, Duration, Timer};
+use embedded_hal::i2c::{Error, ErrorKind};
+
+pub type I2cBus = NoopMutex<RefCell<Twim<'static, TWISPI0>>>;
+pub type SharedI2c = I2cDevice<'static, NoopRawMutex, Twim<'static, TWISPI0>>;
+
+// Attempts of a transaction before giving up, waiting twice as long each time
+const ATTEMPTS: u32 = 3;
+const FIRST_BACKOFF: Duration = Duration::from_millis(1);
+
+// Thingy:52 TWI pins, see Twim::new in main
+const SDA_PIN: usize = 7;
+const SCL_PIN: usize = 8;
+// 100 kHz clock while recovering
+const HALF_PERIOD: Duration = Duration::from_micros(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Fault {
+    Busy,   // not acknowledged, e.g. the device is resetting: retry later
+    Bus,    // bus error or lost arbitration, a device may hold SDA: clock it free
+    Device, // unexpected answer, the device must be initialised again
+}
+
+pub fn classify(kind: ErrorKind) -> Fault {
+    match kind {
+        ErrorKind::NoAcknowledge(_) => Fault::Busy,
+        _ => Fault::Bus,
+    }
+}
+
+pub fn i2c_fault<E: Error>(error: &E) -> Fault {
+    classify(error.kind())
+}
+
+// The I2C bus shared by the IO expander (and LEDs) and the IMU,
+// counting the errors and recoveries for the diagnostics service
+pub struct Bus {
+    i2c: &'static I2cBus,
+    errors: Cell<u32>,
+    recoveries: Cell<u32>,
+}
+
+impl Bus {
+    pub fn new(i2c: &'static I2cBus) -> Self {
+        Bus {
+            i2c,
+            errors: Cell::new(0),
+            recoveries: Cell::new(0),
+        }
+    }
+
+    pub fn device(&self) -> SharedI2c {
+        I2cDevice::new(self.i2c)
+    }
+
+    pub fn record(&self, fault: Fault) {
+        warn!("i2c: {:?}", fault);
+        self.errors.set(self.errors.get().wrapping_add(1));
+    }
+
+    // Little endian errors and recoveries since boot
+    pub fn counters(&self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        buf[0..4].copy_from_slice(&self.errors.get().to_le_bytes());
+        buf[4..8].copy_from_slice(&self.recoveries.get().to_le_bytes());
+        buf
+    }
+
+    // Runs `transaction` until it succeeds, recovering the bus after bus errors.
+    // Gives up on the first device fault, the caller initialises it again.
+    pub async fn retry<T, E>(
+        &self,
+        mut transaction: impl FnMut() -> Result<T, E>,
+        fault: impl Fn(&E) -> Fault,
+    ) -> Result<T, Fault> {
+        let mut backoff = FIRST_BACKOFF;
+        let mut last = Fault::Busy;
+        for _ in 0..ATTEMPTS {
+            match transaction() {
+                Ok(value) => return Ok(value),
+                Err(e) => last = fault(&e),
+            }
+            self.record(last);
+            match last {
+                Fault::Busy => {}
+                Fault::Bus => self.recover(),
+                Fault::Device => break,
+            }
+            Timer::after(backoff).await;
+            backoff = backoff * 2;
+        }
+        Err(last)
+    }
+
+    // A device interrupted mid byte keeps SDA low until it's clocked out: up to 9
+    // clocks on SCL, then a STOP. The TWIM is disabled meanwhile, so its pins (left
+    // open drain by the driver) are plain GPIOs.
+    pub fn recover(&self) {
+        warn!("i2c: recovering the bus");
+        self.recoveries.set(self.recoveries.get().wrapping_add(1));
+        self.i2c.lock(|_| {
+            let twim = unsafe { &*pac::TWIM0::ptr() };
+            let p0 = unsafe { &*pac::P0::ptr() };
+            let scl = 1 << SCL_PIN;
+            let sda = 1 << SDA_PIN;
+            twim.enable.write(|w| w.enable().disabled());
+
+            p0.outset.write(|w| unsafe { w.bits(scl | sda) });
+            p0.dirset.write(|w| unsafe { w.bits(scl | sda) });
+            for _ in 0..9 {
+                if p0.in_.read().bits() & sda != 0 {
+                    break;
+                }
+                p0.outclr.write(|w| unsafe { w.bits(scl) });
+                block_for(HALF_PERIOD);
+                p0.outset.write(|w| unsafe { w.bits(scl) });
+                block_for(HALF_PERIOD);
+            }
+
+            // STOP: SDA rises while SCL is high
+            p0.outclr.write(|w| unsafe { w.bits(scl) });
+            p0.outclr.write(|w| unsafe { w.bits(sda) });
+            block_for(HALF_PERIOD);
+            p0.outset.write(|w| unsafe { w.bits(scl) });
+            block_for(HALF_PERIOD);
+            p0.outset.write(|w| unsafe { w.bits(sda) });
+            block_for(HALF_PERIOD);
+
+            p0.dirclr.write(|w| unsafe { w.bits(scl | sda) });
+            twim.enable.write(|w| w.enable().enabled());
+        });
+    }
+}
@@ -0,0 +1,61 @@
+use defmt::*;
+use embassy_nrf::gpio::Input;
+use embassy_nrf::peripherals::P0_11;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+// Bounces after an edge are ignored this long
+const DEBOUNCE: Duration = Duration::from_millis(10);
+// A press this soon after the previous release is a double click
+const DOUBLE_CLICK_WINDOW: Duration = Duration::from_millis(300);
+// Held this long it's a long press
+const LONG_PRESS: Duration = Duration::from_millis(600);
+// Changes waiting for the control task, so a tap released before it ran still shoots
+pub const BUTTON_EVENTS: usize = 8;
+
+// Button actions, each active until the button is released
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Format)]
+pub struct ButtonState {
+    pub shoot: bool,        // every press shoots, double clicks included
+    pub double_click: bool, // the second press of a double click
+    pub long_press: bool,   // held past LONG_PRESS
+}
+
+// Waits on the button (active low) through GPIOTE, so every press and release is
+// queued to the control task as it happens, however short it is. An edge is told
+// at once, then the bounces are ignored for DEBOUNCE.
+pub async fn button_task(
+    btn: &mut Input<'static, P0_11>,
+    changed: &Channel<NoopRawMutex, ButtonState, BUTTON_EVENTS>,
+) {
+    let mut released_at: Option<Instant> = None;
+    loop {
+        btn.wait_for_low().await;
+        let double_click =
+            released_at.is_some_and(|released_at| released_at.elapsed() <= DOUBLE_CLICK_WINDOW);
+        let mut state = ButtonState {
+            shoot: true,
+            double_click,
+            long_press: false,
+        };
+        debug!("button: {:?}", state);
+        changed.send(state).await;
+        Timer::after(DEBOUNCE).await;
+
+        if with_timeout(LONG_PRESS - DEBOUNCE, btn.wait_for_high())
+            .await
+            .is_err()
+        {
+            state.long_press = true;
+            debug!("button: {:?}", state);
+            changed.send(state).await;
+            btn.wait_for_high().await;
+        }
+        debug!("button: released");
+        changed.send(ButtonState::default()).await;
+        // The press after a double click starts over
+        released_at = (!double_click).then(Instant::now);
+        Timer::after(DEBOUNCE).await;
+    }
+}
@@ -0,0 +1,318 @@
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use libm::{copysignf, sqrtf};
+use nrf_softdevice::Flash;
+
+use crate::ble::Connections;
+use crate::config::AlignedBuffer;
+use crate::fusion::Vector3;
+use crate::unwrap_notify;
+
+// Flash page reserved for the calibration in memory.x
+extern "C" {
+    static __calibration_start: u32;
+}
+
+fn calibration_address() -> u32 {
+    unsafe { &__calibration_start as *const u32 as u32 }
+}
+
+// Marks a written calibration page, erased flash reads as 0xFFFFFFFF
+const CALIBRATION_MAGIC: u32 = 0xCA11_0001;
+const CALIBRATION_SIZE: usize = 52;
+
+pub const STANDARD_GRAVITY: f32 = 9.80665; // m/s²
+
+// A sample spinning faster than this restarts the stationary phase
+const STATIONARY_GYRO_LIMIT: f32 = 0.3; // rad/s
+
+// The rotation must cover at least this range on every axis, the earth field is 25 to 65 µT
+const MIN_MAG_RANGE: f32 = 20.0; // µT
+
+// Sensor corrections, applied to every sample before the classification
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Calibration {
+    pub accel_bias: Vector3, // m/s²
+    pub gyro_bias: Vector3,  // rad/s
+    pub mag_bias: Vector3,   // µT, hard iron
+    pub mag_scale: Vector3,  // soft iron, per axis
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            accel_bias: (0.0, 0.0, 0.0),
+            gyro_bias: (0.0, 0.0, 0.0),
+            mag_bias: (0.0, 0.0, 0.0),
+            mag_scale: (1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Calibration {
+    pub fn apply(
+        &self,
+        accel: Vector3,
+        gyro: Vector3,
+        mag: Vector3,
+    ) -> (Vector3, Vector3, Vector3) {
+        let sub = |v: Vector3, b: Vector3| (v.0 - b.0, v.1 - b.1, v.2 - b.2);
+        let mag = sub(mag, self.mag_bias);
+        (
+            sub(accel, self.accel_bias),
+            sub(gyro, self.gyro_bias),
+            (
+                mag.0 * self.mag_scale.0,
+                mag.1 * self.mag_scale.1,
+                mag.2 * self.mag_scale.2,
+            ),
+        )
+    }
+
+    // Comparisons are false for NaN, so it is rejected too
+    fn is_valid(&self) -> bool {
+        let within = |v: Vector3, range: core::ops::Range<f32>| {
+            range.contains(&v.0) && range.contains(&v.1) && range.contains(&v.2)
+        };
+        within(self.accel_bias, -3.0..3.0)
+            && within(self.gyro_bias, -0.5..0.5)
+            && within(self.mag_bias, -500.0..500.0)
+            && within(self.mag_scale, 0.2..5.0)
+    }
+
+    fn to_bytes(self) -> [u8; CALIBRATION_SIZE] {
+        let mut buf = [0u8; CALIBRATION_SIZE];
+        buf[0..4].copy_from_slice(&CALIBRATION_MAGIC.to_le_bytes());
+        let vectors = [
+            self.accel_bias,
+            self.gyro_bias,
+            self.mag_bias,
+            self.mag_scale,
+        ];
+        for (i, v) in vectors.iter().enumerate() {
+            let start = 4 + 12 * i;
+            buf[start..start + 4].copy_from_slice(&v.0.to_le_bytes());
+            buf[start + 4..start + 8].copy_from_slice(&v.1.to_le_bytes());
+            buf[start + 8..start + 12].copy_from_slice(&v.2.to_le_bytes());
+        }
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; CALIBRATION_SIZE]) -> Option<Self> {
+        let word = |i: usize| [buf[i], buf[i + 1], buf[i + 2], buf[i + 3]];
+        if u32::from_le_bytes(word(0)) != CALIBRATION_MAGIC {
+            return None;
+        }
+
+        let vector = |i: usize| {
+            let start = 4 + 12 * i;
+            (
+                f32::from_le_bytes(word(start)),
+                f32::from_le_bytes(word(start + 4)),
+                f32::from_le_bytes(word(start + 8)),
+            )
+        };
+        let calibration = Calibration {
+            accel_bias: vector(0),
+            gyro_bias: vector(1),
+            mag_bias: vector(2),
+            mag_scale: vector(3),
+        };
+        calibration.is_valid().then_some(calibration)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Progress {
+    Stationary,
+    Rotation,
+    Done(Option<Calibration>), // None when the result is out of range
+}
+
+// Collects the calibration samples in two phases:
+// lying flat and still for the accelerometer and gyroscope biases,
+// then rotated in every direction for the magnetometer extremes.
+pub struct Calibrator {
+    stationary_samples: u32,
+    rotation_samples: u32,
+    samples: u32,
+    accel_sum: Vector3,
+    gyro_sum: Vector3,
+    mag_min: Vector3,
+    mag_max: Vector3,
+}
+
+impl Calibrator {
+    // Without rotation samples the magnetometer correction stays neutral
+    pub const fn new(stationary_samples: u32, rotation_samples: u32) -> Self {
+        Calibrator {
+            stationary_samples,
+            rotation_samples,
+            samples: 0,
+            accel_sum: (0.0, 0.0, 0.0),
+            gyro_sum: (0.0, 0.0, 0.0),
+            mag_min: (f32::MAX, f32::MAX, f32::MAX),
+            mag_max: (f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    // Raw (uncalibrated) sample, accel in m/s², gyro in rad/s and mag in µT
+    pub fn update(&mut self, accel: Vector3, gyro: Vector3, mag: Vector3) -> Progress {
+        if self.samples < self.stationary_samples {
+            if sqrtf(gyro.0 * gyro.0 + gyro.1 * gyro.1 + gyro.2 * gyro.2) > STATIONARY_GYRO_LIMIT {
+                if self.samples > 0 {
+                    warn!("calibration: moved, keep the device still");
+                }
+                self.samples = 0;
+                self.accel_sum = (0.0, 0.0, 0.0);
+                self.gyro_sum = (0.0, 0.0, 0.0);
+                return Progress::Stationary;
+            }
+
+            let add = |s: Vector3, v: Vector3| (s.0 + v.0, s.1 + v.1, s.2 + v.2);
+            self.accel_sum = add(self.accel_sum, accel);
+            self.gyro_sum = add(self.gyro_sum, gyro);
+            self.samples += 1;
+            return Progress::Stationary;
+        }
+
+        if self.samples < self.stationary_samples + self.rotation_samples {
+            self.mag_min = (
+                self.mag_min.0.min(mag.0),
+                self.mag_min.1.min(mag.1),
+                self.mag_min.2.min(mag.2),
+            );
+            self.mag_max = (
+                self.mag_max.0.max(mag.0),
+                self.mag_max.1.max(mag.1),
+                self.mag_max.2.max(mag.2),
+            );
+            self.samples += 1;
+            return Progress::Rotation;
+        }
+
+        Progress::Done(self.calibration())
+    }
+
+    fn calibration(&self) -> Option<Calibration> {
+        let n = self.stationary_samples.max(1) as f32;
+        let accel = (
+            self.accel_sum.0 / n,
+            self.accel_sum.1 / n,
+            self.accel_sum.2 / n,
+        );
+        let gyro = (
+            self.gyro_sum.0 / n,
+            self.gyro_sum.1 / n,
+            self.gyro_sum.2 / n,
+        );
+
+        // Lying flat, only the vertical axis should measure gravity
+        let expected = (0.0, 0.0, copysignf(STANDARD_GRAVITY, accel.2));
+        let mut calibration = Calibration {
+            accel_bias: (
+                accel.0 - expected.0,
+                accel.1 - expected.1,
+                accel.2 - expected.2,
+            ),
+            gyro_bias: gyro,
+            ..Calibration::default()
+        };
+
+        // Hard iron is the center of the extremes, soft iron scales
+        // every axis radius to the average one
+        let range = (
+            self.mag_max.0 - self.mag_min.0,
+            self.mag_max.1 - self.mag_min.1,
+            self.mag_max.2 - self.mag_min.2,
+        );
+        if range.0 > MIN_MAG_RANGE && range.1 > MIN_MAG_RANGE && range.2 > MIN_MAG_RANGE {
+            let average = (range.0 + range.1 + range.2) / 3.0;
+            calibration.mag_bias = (
+                (self.mag_max.0 + self.mag_min.0) / 2.0,
+                (self.mag_max.1 + self.mag_min.1) / 2.0,
+                (self.mag_max.2 + self.mag_min.2) / 2.0,
+            );
+            calibration.mag_scale = (average / range.0, average / range.1, average / range.2);
+        } else if self.rotation_samples > 0 {
+            warn!("calibration: not enough rotation, magnetometer left uncalibrated");
+        }
+
+        if !calibration.is_valid() {
+            warn!(
+                "calibration: out of range, was the device lying flat? {:?}",
+                calibration
+            );
+            return None;
+        }
+        Some(calibration)
+    }
+}
+
+// GATT Service
+#[nrf_softdevice::gatt_service(uuid = "0000DAD3-0000-0000-0000-000000000000")]
+pub struct CalibrationService {
+    #[characteristic(uuid = "0000DAD3-0000-0000-0000-000000000001", read, notify)]
+    status: u8, // see CalibrationStatus
+
+    #[characteristic(uuid = "0000DAD3-0000-0000-0000-000000000002", write)]
+    start: u8, // any write starts a calibration
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum CalibrationStatus {
+    Uncalibrated = 0,
+    Calibrated = 1,
+    Running = 2,
+    Failed = 3, // the previous calibration is kept
+}
+
+impl CalibrationService {
+    // Without connections (e.g. at boot) only the read value is updated
+    pub fn set_status(&self, status: CalibrationStatus, connections: &Connections) {
+        info!("calibration: {:?}", status);
+        unwrap!(self.status_set(&(status as u8)));
+        connections.for_each(|connection| {
+            unwrap_notify(
+                self.status_notify(connection, &(status as u8)),
+                "calibration",
+            )
+        });
+    }
+
+    // The control task runs the requested calibration on the next samples
+    pub fn on_write(&self, event: CalibrationServiceEvent, request: &Signal<NoopRawMutex, ()>) {
+        match event {
+            CalibrationServiceEvent::StartWrite(_) => request.signal(()),
+            CalibrationServiceEvent::StatusCccdWrite { notifications } => {
+                info!("calibration notifications: {}", notifications)
+            }
+        }
+    }
+}
+
+pub async fn load_calibration(flash: &mut Flash) -> Option<Calibration> {
+    let mut buf = [0u8; CALIBRATION_SIZE];
+    if let Err(e) = flash.read(calibration_address(), &mut buf).await {
+        warn!("could not read calibration: {:?}", e);
+        return None;
+    }
+    Calibration::from_bytes(&buf)
+}
+
+pub async fn store_calibration(flash: &mut Flash, calibration: &Calibration) {
+    let address = calibration_address();
+    if let Err(e) = flash
+        .erase(address, address + Flash::ERASE_SIZE as u32)
+        .await
+    {
+        warn!("could not erase calibration: {:?}", e);
+        return;
+    }
+    let buf = AlignedBuffer(calibration.to_bytes());
+    if let Err(e) = flash.write(address, &buf.0).await {
+        warn!("could not write calibration: {:?}", e);
+    }
+}
@@ -0,0 +1,328 @@
+use core::cell::Cell;
+
+use defmt::*;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+use nrf_softdevice::Flash;
+
+use crate::bond::{store_bonds, Bonder};
+use crate::calibration::{store_calibration, Calibration, STANDARD_GRAVITY};
+use crate::gesture::{GestureTuning, GESTURE_TUNING_LEN};
+
+// Flash page reserved for the configuration in memory.x
+extern "C" {
+    static __config_start: u32;
+}
+
+fn config_address() -> u32 {
+    unsafe { &__config_start as *const u32 as u32 }
+}
+
+// Marks a written configuration page, erased flash reads as 0xFFFFFFFF.
+// Bumped when the layout changes, an older page then falls back to defaults.
+const CONFIG_MAGIC: u32 = 0x7417_0006;
+const CONFIG_SIZE: usize = 40 + GESTURE_TUNING_LEN;
+
+// Flash writes must come from a word aligned buffer
+#[repr(align(4))]
+pub struct AlignedBuffer<const N: usize>(pub [u8; N]);
+
+// Everything a central can change through the Config service, persisted as one page:
+// the classifier thresholds and tuning, the analog axes shape, the volume and the sleep timeout
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Settings {
+    pub pitch_threshold: f32, // rad, up/down when the tilt is beyond ±threshold
+    pub roll_threshold: f32,  // rad, left/right when the tilt is beyond ±threshold
+    pub jump_threshold: f32,  // m/s², jump when the vertical acceleration is above it
+    pub spin_threshold: f32,  // rad/s, spin when the yaw rate is above it
+    pub deadzone: f32,        // fraction of the analog axes range reported as zero
+    pub curve: f32,           // analog response exponent, 1 is linear
+    pub turn_threshold: f32,  // rad, turn when the heading moved beyond ±threshold
+    pub volume: f32,          // speaker loudness, 0 mutes it
+    pub sleep_timeout: f32,   // s, System OFF after being still that long, 0 never sleeps
+    // Hysteresis below each threshold and minimum time between two changes of a gesture
+    pub tuning: GestureTuning,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            pitch_threshold: 0.3,
+            roll_threshold: 0.3,
+            jump_threshold: -6.5,
+            spin_threshold: 3.0,
+            deadzone: 0.05,
+            curve: 1.0,
+            turn_threshold: core::f32::consts::FRAC_PI_4,
+            volume: 1.0,
+            sleep_timeout: 300.0,
+            tuning: GestureTuning::default(),
+        }
+    }
+}
+
+impl Settings {
+    // Comparisons are false for NaN, so it is rejected too
+    fn is_valid(&self) -> bool {
+        let tilt_range = 0.0..core::f32::consts::FRAC_PI_2;
+        tilt_range.contains(&self.pitch_threshold)
+            && tilt_range.contains(&self.roll_threshold)
+            && (-19.6..0.0).contains(&self.jump_threshold) // up to 2g
+            && (0.0..34.9).contains(&self.spin_threshold) // up to 2000 °/s
+            && (0.0..0.9).contains(&self.deadzone)
+            && (0.2..=5.0).contains(&self.curve)
+            && (0.0..core::f32::consts::PI).contains(&self.turn_threshold)
+            && (0.0..=1.0).contains(&self.volume)
+            && (0.0..=3600.0).contains(&self.sleep_timeout)
+            && self.tuning.left_right.is_valid(self.roll_threshold, 0.0)
+            && self.tuning.up_down.is_valid(self.pitch_threshold, 0.0)
+            && self.tuning.jump.is_valid(self.jump_threshold, -STANDARD_GRAVITY)
+            && self.tuning.spin.is_valid(self.spin_threshold, 0.0)
+            && self.tuning.turn.is_valid(self.turn_threshold, 0.0)
+    }
+
+    fn to_bytes(self) -> [u8; CONFIG_SIZE] {
+        let mut buf = [0u8; CONFIG_SIZE];
+        buf[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.pitch_threshold.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.roll_threshold.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.jump_threshold.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.spin_threshold.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.deadzone.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.curve.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.turn_threshold.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.volume.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.sleep_timeout.to_le_bytes());
+        buf[40..].copy_from_slice(&self.tuning.to_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; CONFIG_SIZE]) -> Option<Self> {
+        let word = |i: usize| [buf[i], buf[i + 1], buf[i + 2], buf[i + 3]];
+        if u32::from_le_bytes(word(0)) != CONFIG_MAGIC {
+            return None;
+        }
+
+        let mut tuning = [0u8; GESTURE_TUNING_LEN];
+        tuning.copy_from_slice(&buf[40..]);
+        let settings = Settings {
+            pitch_threshold: f32::from_le_bytes(word(4)),
+            roll_threshold: f32::from_le_bytes(word(8)),
+            jump_threshold: f32::from_le_bytes(word(12)),
+            spin_threshold: f32::from_le_bytes(word(16)),
+            deadzone: f32::from_le_bytes(word(20)),
+            curve: f32::from_le_bytes(word(24)),
+            turn_threshold: f32::from_le_bytes(word(28)),
+            volume: f32::from_le_bytes(word(32)),
+            sleep_timeout: f32::from_le_bytes(word(36)),
+            tuning: GestureTuning::from_bytes(&tuning),
+        };
+        settings.is_valid().then_some(settings)
+    }
+}
+
+// GATT Service
+#[nrf_softdevice::gatt_service(uuid = "0000DAD1-0000-0000-0000-000000000000")]
+pub struct ConfigService {
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000001",
+        read,
+        write,
+        security = "just_works"
+    )]
+    pitch_threshold: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000002",
+        read,
+        write,
+        security = "just_works"
+    )]
+    roll_threshold: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000003",
+        read,
+        write,
+        security = "just_works"
+    )]
+    jump_threshold: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000004",
+        read,
+        write,
+        security = "just_works"
+    )]
+    spin_threshold: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000005",
+        read,
+        write,
+        security = "just_works"
+    )]
+    deadzone: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000006",
+        read,
+        write,
+        security = "just_works"
+    )]
+    curve: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000007",
+        read,
+        write,
+        security = "just_works"
+    )]
+    turn_threshold: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000008",
+        read,
+        write,
+        security = "just_works"
+    )]
+    volume: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-000000000009",
+        read,
+        write,
+        security = "just_works"
+    )]
+    sleep_timeout: f32,
+
+    #[characteristic(
+        uuid = "0000DAD1-0000-0000-0000-00000000000A",
+        read,
+        write,
+        security = "just_works"
+    )]
+    tuning: [u8; GESTURE_TUNING_LEN], // see GestureTuning::to_bytes
+}
+
+impl ConfigService {
+    pub fn set_settings(&self, settings: &Settings) {
+        unwrap!(self.pitch_threshold_set(&settings.pitch_threshold));
+        unwrap!(self.roll_threshold_set(&settings.roll_threshold));
+        unwrap!(self.jump_threshold_set(&settings.jump_threshold));
+        unwrap!(self.spin_threshold_set(&settings.spin_threshold));
+        unwrap!(self.deadzone_set(&settings.deadzone));
+        unwrap!(self.curve_set(&settings.curve));
+        unwrap!(self.turn_threshold_set(&settings.turn_threshold));
+        unwrap!(self.volume_set(&settings.volume));
+        unwrap!(self.sleep_timeout_set(&settings.sleep_timeout));
+        unwrap!(self.tuning_set(&settings.tuning.to_bytes()));
+    }
+
+    // Validate a write, apply it live and request it to be persisted.
+    // An invalid value is reverted so a read shows the setting in use.
+    pub fn on_write(
+        &self,
+        event: ConfigServiceEvent,
+        settings: &Cell<Settings>,
+        changed: &Signal<NoopRawMutex, Settings>,
+    ) {
+        let mut new_settings = settings.get();
+        match event {
+            ConfigServiceEvent::PitchThresholdWrite(value) => new_settings.pitch_threshold = value,
+            ConfigServiceEvent::RollThresholdWrite(value) => new_settings.roll_threshold = value,
+            ConfigServiceEvent::JumpThresholdWrite(value) => new_settings.jump_threshold = value,
+            ConfigServiceEvent::SpinThresholdWrite(value) => new_settings.spin_threshold = value,
+            ConfigServiceEvent::DeadzoneWrite(value) => new_settings.deadzone = value,
+            ConfigServiceEvent::CurveWrite(value) => new_settings.curve = value,
+            ConfigServiceEvent::TurnThresholdWrite(value) => new_settings.turn_threshold = value,
+            ConfigServiceEvent::VolumeWrite(value) => new_settings.volume = value,
+            ConfigServiceEvent::SleepTimeoutWrite(value) => new_settings.sleep_timeout = value,
+            ConfigServiceEvent::TuningWrite(value) => {
+                new_settings.tuning = GestureTuning::from_bytes(&value)
+            }
+        }
+
+        if !new_settings.is_valid() {
+            warn!("invalid settings: {:?}", new_settings);
+            self.set_settings(&settings.get());
+            return;
+        }
+
+        info!("settings: {:?}", new_settings);
+        settings.set(new_settings);
+        changed.signal(new_settings);
+    }
+}
+
+pub async fn load_settings(flash: &mut Flash) -> Settings {
+    let mut buf = [0u8; CONFIG_SIZE];
+    if let Err(e) = flash.read(config_address(), &mut buf).await {
+        warn!("could not read config: {:?}", e);
+        return Settings::default();
+    }
+
+    match Settings::from_bytes(&buf) {
+        Some(settings) => settings,
+        None => {
+            info!("no stored config, using defaults");
+            Settings::default()
+        }
+    }
+}
+
+async fn store_settings(flash: &mut Flash, settings: &Settings) {
+    let address = config_address();
+    if let Err(e) = flash
+        .erase(address, address + Flash::ERASE_SIZE as u32)
+        .await
+    {
+        warn!("could not erase config: {:?}", e);
+        return;
+    }
+    let buf = AlignedBuffer(settings.to_bytes());
+    if let Err(e) = flash.write(address, &buf.0).await {
+        warn!("could not write config: {:?}", e);
+    }
+}
+
+// Persist the settings, the calibration and the bonds when they change, so they survive reboot.
+// The flash is shared with the firmware update, it's only locked while storing
+pub async fn config_task(
+    flash: &Mutex<NoopRawMutex, Flash>,
+    settings_changed: &Signal<NoopRawMutex, Settings>,
+    calibration_changed: &Signal<NoopRawMutex, Calibration>,
+    bonder: &Bonder,
+) {
+    loop {
+        let changed = select3(
+            settings_changed.wait(),
+            calibration_changed.wait(),
+            bonder.changed.wait(),
+        );
+        let changed = changed.await;
+        let mut flash = flash.lock().await;
+        match changed {
+            Either3::First(settings) => {
+                // Writing back the stored settings would only wear the flash
+                if load_settings(&mut flash).await == settings {
+                    info!("settings unchanged");
+                } else {
+                    store_settings(&mut flash, &settings).await;
+                    info!("settings stored");
+                }
+            }
+            Either3::Second(calibration) => {
+                store_calibration(&mut flash, &calibration).await;
+                info!("calibration stored");
+            }
+            Either3::Third(()) => {
+                store_bonds(&mut flash, bonder).await;
+                info!("bonds stored");
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// The control state decided from the sensors and the button, as the
+// characteristics and the HID report tell it
+use defmt::Format;
+
+// Type for meaningfull code
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Format)]
+pub enum LeftRight {
+    Left,
+    #[default]
+    None,
+    Right,
+}
+
+impl From<LeftRight> for i8 {
+    fn from(lr: LeftRight) -> Self {
+        match lr {
+            LeftRight::Left => 1,
+            LeftRight::None => 0,
+            LeftRight::Right => -1,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Format)]
+pub enum UpDown {
+    Up,
+    #[default]
+    None,
+    Down,
+}
+
+impl From<UpDown> for i8 {
+    fn from(ud: UpDown) -> Self {
+        match ud {
+            UpDown::Up => -1,
+            UpDown::None => 0,
+            UpDown::Down => 1,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Format)]
+pub struct Control {
+    pub left_right: LeftRight,
+    pub up_down: UpDown,
+    pub shoot: bool,
+    pub jump: bool,
+    pub spin: bool,
+    pub turn: LeftRight,
+    pub double_click: bool,
+    pub long_press: bool,
+}
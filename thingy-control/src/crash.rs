@@ -0,0 +1,222 @@
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::panic::{Location, PanicInfo};
+use core::ptr::{addr_of_mut, read_volatile, write_volatile};
+
+use cortex_m::peripheral::SCB;
+use cortex_m::register::{lr, msp, pc};
+use cortex_m_rt::{exception, ExceptionFrame};
+use defmt::*;
+
+// RAM kept across resets (not power loss), after the firmware's RAM, see memory.x
+extern "C" {
+    static mut __crash_start: u32;
+}
+const CRASH_REGION_SIZE: usize = 256;
+
+// Marks a written report, RAM holds garbage after power on
+const CRASH_MAGIC: u32 = 0xC2A5_0001;
+
+const FILE_LEN: usize = 32;
+const MESSAGE_LEN: usize = 96;
+// Kind, line, 5 registers, file and message
+pub const CRASH_REPORT_LEN: usize = 1 + 4 + 5 * 4 + FILE_LEN + MESSAGE_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+#[repr(u8)]
+pub enum CrashKind {
+    None = 0,
+    Panic = 1,
+    HardFault = 2,
+}
+
+// Written by the panic and hard fault handlers right before the reset
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CrashReport {
+    kind: u8, // CrashKind, checked when read back
+    line: u32,
+    registers: [u32; 5],        // pc, lr, sp, cfsr, hfsr
+    file: [u8; FILE_LEN],       // end of the path, NUL padded
+    message: [u8; MESSAGE_LEN], // truncated, NUL padded
+}
+
+#[repr(C)]
+struct Retained {
+    magic: u32,
+    report: CrashReport,
+}
+
+const _: () = assert!(size_of::<Retained>() <= CRASH_REGION_SIZE);
+
+fn retained() -> *mut Retained {
+    unsafe { addr_of_mut!(__crash_start) as *mut Retained }
+}
+
+impl CrashReport {
+    pub const fn empty() -> Self {
+        CrashReport {
+            kind: CrashKind::None as u8,
+            line: 0,
+            registers: [0; 5],
+            file: [0; FILE_LEN],
+            message: [0; MESSAGE_LEN],
+        }
+    }
+
+    fn new(kind: CrashKind, location: Option<&Location>, registers: [u32; 5]) -> Self {
+        let mut report = CrashReport {
+            kind: kind as u8,
+            registers,
+            ..CrashReport::empty()
+        };
+        if let Some(location) = location {
+            // The end of the path tells the file apart
+            let file = location.file().as_bytes();
+            let file = &file[file.len().saturating_sub(FILE_LEN)..];
+            report.file[..file.len()].copy_from_slice(file);
+            report.line = location.line();
+        }
+        report
+    }
+
+    pub fn kind(&self) -> CrashKind {
+        match self.kind {
+            1 => CrashKind::Panic,
+            2 => CrashKind::HardFault,
+            _ => CrashKind::None,
+        }
+    }
+
+    // Little endian [kind u8, line u32, pc u32, lr u32, sp u32, cfsr u32, hfsr u32,
+    // file [u8; 32], message [u8; 96]], as the Diagnostics service exposes it
+    pub fn to_bytes(&self) -> [u8; CRASH_REPORT_LEN] {
+        let mut buf = [0u8; CRASH_REPORT_LEN];
+        buf[0] = self.kind;
+        buf[1..5].copy_from_slice(&self.line.to_le_bytes());
+        for (i, register) in self.registers.iter().enumerate() {
+            buf[5 + 4 * i..9 + 4 * i].copy_from_slice(&register.to_le_bytes());
+        }
+        let file_start = 5 + 4 * self.registers.len();
+        buf[file_start..file_start + FILE_LEN].copy_from_slice(&self.file);
+        buf[file_start + FILE_LEN..].copy_from_slice(&self.message);
+        buf
+    }
+
+    // Report of the crash which reset the chip, kept across the following resets
+    // until a central acknowledges it, so it isn't lost when nobody was connected
+    pub fn read() -> Option<Self> {
+        let retained = retained();
+        unsafe {
+            if read_volatile(addr_of_mut!((*retained).magic)) != CRASH_MAGIC {
+                return None;
+            }
+            let report = read_volatile(addr_of_mut!((*retained).report));
+            match report.kind() {
+                CrashKind::None => None,
+                _ => Some(report),
+            }
+        }
+    }
+
+    pub fn clear() {
+        unsafe { write_volatile(addr_of_mut!((*retained()).magic), 0) };
+    }
+
+    fn store(&self) {
+        let retained = retained();
+        unsafe {
+            write_volatile(addr_of_mut!((*retained).report), *self);
+            write_volatile(addr_of_mut!((*retained).magic), CRASH_MAGIC);
+        }
+    }
+}
+
+impl Format for CrashReport {
+    fn format(&self, f: Formatter) {
+        defmt::write!(
+            f,
+            "{:?} at {}:{}: {} (pc {=u32:#x}, lr {=u32:#x}, sp {=u32:#x}, cfsr {=u32:#x}, hfsr {=u32:#x})",
+            self.kind(),
+            text(&self.file),
+            self.line,
+            text(&self.message),
+            self.registers[0],
+            self.registers[1],
+            self.registers[2],
+            self.registers[3],
+            self.registers[4]
+        )
+    }
+}
+
+// Up to the NUL padding, the truncation may have cut a character
+fn text(buf: &[u8]) -> &str {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    match core::str::from_utf8(&buf[..len]) {
+        Ok(text) => text,
+        Err(e) => unwrap!(core::str::from_utf8(&buf[..e.valid_up_to()])),
+    }
+}
+
+// Drops what doesn't fit
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn fault_status() -> (u32, u32) {
+    let scb = unsafe { &*SCB::PTR };
+    (scb.cfsr.read(), scb.hfsr.read())
+}
+
+// Keeps the report for the next boot, then resets so the controller comes back
+fn crash(location: Option<&Location>, message: fmt::Arguments) -> ! {
+    let (cfsr, hfsr) = fault_status();
+    let registers = [pc::read(), lr::read(), msp::read(), cfsr, hfsr];
+    let mut report = CrashReport::new(CrashKind::Panic, location, registers);
+    let mut writer = Truncating {
+        buf: &mut report.message,
+        len: 0,
+    };
+    let _ = writer.write_fmt(message);
+    report.store();
+    SCB::sys_reset();
+}
+
+// Nobody reads the RTT logs in a player's hand, the panic is told by the
+// Diagnostics service after the reset
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    error!("{}", Display2Format(info));
+    // The message API differs between nightlies, the Display of the info is stable
+    // but repeats the location ahead of the message
+    crash(info.location(), format_args!("{}", info))
+}
+
+// `defmt::panic!` and `unwrap!` failures, their message only goes to the log
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    cortex_m::interrupt::disable();
+    crash(None, format_args!("defmt panic, see the log"));
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let (cfsr, hfsr) = fault_status();
+    let registers = [frame.pc(), frame.lr(), frame as *const _ as u32, cfsr, hfsr];
+    let report = CrashReport::new(CrashKind::HardFault, None, registers);
+    error!("{}", report);
+    report.store();
+    SCB::sys_reset();
+}
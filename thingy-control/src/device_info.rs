@@ -0,0 +1,76 @@
+use embassy_nrf::pac;
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{self, RegisterError};
+use nrf_softdevice::ble::Uuid;
+use nrf_softdevice::Softdevice;
+
+// Assigned numbers: https://www.bluetooth.com/specifications/assigned-numbers/
+const DEVICE_INFORMATION_SERVICE: Uuid = Uuid::new_16(0x180a);
+const MODEL_NUMBER: Uuid = Uuid::new_16(0x2a24);
+const SERIAL_NUMBER: Uuid = Uuid::new_16(0x2a25);
+const FIRMWARE_REVISION: Uuid = Uuid::new_16(0x2a26);
+const HARDWARE_REVISION: Uuid = Uuid::new_16(0x2a27);
+const SOFTWARE_REVISION: Uuid = Uuid::new_16(0x2a28);
+const MANUFACTURER_NAME: Uuid = Uuid::new_16(0x2a29);
+
+const MANUFACTURER_NAME_VALUE: &str = "Nordic Semiconductor";
+const MODEL_NUMBER_VALUE: &str = "Thingy:52";
+const HARDWARE_REVISION_VALUE: &str = "nRF52832_xxAA";
+const FIRMWARE_REVISION_VALUE: &str = env!("CARGO_PKG_VERSION");
+const SOFTWARE_REVISION_VALUE: &str = env!("GIT_HASH"); // injected by build.rs
+
+// FICR DEVICEID is a 64 bits random number unique for each chip
+fn serial_number() -> [u8; 16] {
+    let ficr = unsafe { &*pac::FICR::ptr() };
+    let device_id =
+        (ficr.deviceid[1].read().bits() as u64) << 32 | ficr.deviceid[0].read().bits() as u64;
+
+    let mut serial = [0u8; 16];
+    for (i, digit) in serial.iter_mut().enumerate() {
+        let nibble = (device_id >> (60 - 4 * i)) & 0xf;
+        *digit = b"0123456789ABCDEF"[nibble as usize];
+    }
+    serial
+}
+
+// All characteristics are read-only strings, and the gatt_service macro only
+// supports fixed size values, so the service is built by hand.
+pub struct DeviceInformationService {}
+
+pub enum DeviceInformationServiceEvent {}
+
+impl DeviceInformationService {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service_builder = ServiceBuilder::new(sd, DEVICE_INFORMATION_SERVICE)?;
+
+        let serial = serial_number();
+        for (uuid, value) in [
+            (MANUFACTURER_NAME, MANUFACTURER_NAME_VALUE.as_bytes()),
+            (MODEL_NUMBER, MODEL_NUMBER_VALUE.as_bytes()),
+            (SERIAL_NUMBER, &serial[..]),
+            (HARDWARE_REVISION, HARDWARE_REVISION_VALUE.as_bytes()),
+            (FIRMWARE_REVISION, FIRMWARE_REVISION_VALUE.as_bytes()),
+            (SOFTWARE_REVISION, SOFTWARE_REVISION_VALUE.as_bytes()),
+        ] {
+            let characteristic = service_builder.add_characteristic(
+                uuid,
+                Attribute::new(value),
+                Metadata::new(Properties::new().read()),
+            )?;
+            let _handle = characteristic.build();
+        }
+
+        let _service_handle = service_builder.build();
+
+        Ok(DeviceInformationService {})
+    }
+}
+
+impl gatt_server::Service for DeviceInformationService {
+    type Event = DeviceInformationServiceEvent;
+
+    fn on_write(&self, _handle: u16, _data: &[u8]) -> Option<Self::Event> {
+        None
+    }
+}
@@ -0,0 +1,323 @@
+use defmt::*;
+use embassy_boot_nrf::{FirmwareUpdater, FirmwareUpdaterConfig};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::NorFlash;
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{self, NotifyValueError, RegisterError};
+use nrf_softdevice::ble::{SecurityMode, Uuid};
+use nrf_softdevice::{Flash, RawError, Softdevice};
+
+use crate::ble::{Connections, MAX_CONNECTIONS};
+use crate::config::AlignedBuffer;
+use crate::{unwrap_notify, Server};
+
+// Flash partitions shared with the bootloader in memory.x
+extern "C" {
+    static __bootloader_active_start: u32;
+    static __bootloader_active_end: u32;
+    static __bootloader_dfu_start: u32;
+}
+
+fn max_image_size() -> u32 {
+    unsafe {
+        &__bootloader_active_end as *const u32 as u32
+            - &__bootloader_active_start as *const u32 as u32
+    }
+}
+
+fn dfu_address() -> u32 {
+    unsafe { &__bootloader_dfu_start as *const u32 as u32 }
+}
+
+// Ed25519 key the images must be signed with, set by build.rs
+const PUBLIC_KEY: &[u8; 32] = include_bytes!(concat!(env!("OUT_DIR"), "/dfu_public_key.bin"));
+
+// Chunks waiting to be written, the host doesn't send more before they are acknowledged
+pub const DFU_WINDOW: usize = 4;
+// Packet data after its u32 offset, fits a 247 bytes MTU
+const CHUNK_MAX_SIZE: usize = 240;
+const SIGNATURE_SIZE: usize = 64;
+// Wait for the TX queue to drain before notifying the status again
+const STATUS_RETRY: Duration = Duration::from_millis(10);
+
+// Control opcodes, followed by their arguments
+const START: u8 = 0x01; // u32 image size
+const FINISH: u8 = 0x02; // signature of the SHA-512 of the image
+const ABORT: u8 = 0x03;
+
+// 0000DAD6-0000-0000-0000-00000000000X, little endian
+const fn dfu_uuid(characteristic: u8) -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    uuid[0] = characteristic;
+    uuid[12] = 0xd6;
+    uuid[13] = 0xda;
+    uuid
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum DfuStatus {
+    Idle = 0,
+    Receiving = 1, // with the offset written so far
+    Verified = 2,  // the device reboots and the bootloader swaps the images
+    Failed = 3,
+}
+
+pub enum DfuServiceEvent {
+    Start {
+        size: u32,
+    },
+    Chunk {
+        offset: u32,
+        len: usize,
+        data: AlignedBuffer<CHUNK_MAX_SIZE>, // the flash writes from word aligned buffers
+    },
+    Finish {
+        signature: [u8; SIGNATURE_SIZE],
+    },
+    Abort,
+}
+
+// Packets are written without response and their length varies, which the
+// gatt_service macro doesn't support, so the service is built by hand.
+pub struct DfuService {
+    control: u16,
+    packet: u16,
+    status: u16,
+}
+
+impl DfuService {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service_builder = ServiceBuilder::new(sd, Uuid::new_128(&dfu_uuid(0)))?;
+        // Only a paired central may send an update, like it controls the game
+        let security = SecurityMode::JustWorks;
+
+        let control = service_builder.add_characteristic(
+            Uuid::new_128(&dfu_uuid(1)),
+            Attribute::new([0u8; 1 + SIGNATURE_SIZE])
+                .variable_len((1 + SIGNATURE_SIZE) as u16)
+                .security(security),
+            Metadata::new(Properties::new().write()),
+        )?;
+        let control_handle = control.build();
+
+        let packet = service_builder.add_characteristic(
+            Uuid::new_128(&dfu_uuid(2)),
+            Attribute::new([0u8; 4 + CHUNK_MAX_SIZE])
+                .variable_len((4 + CHUNK_MAX_SIZE) as u16)
+                .security(security),
+            Metadata::new(Properties::new().write_without_response()),
+        )?;
+        let packet_handle = packet.build();
+
+        let status = service_builder.add_characteristic(
+            Uuid::new_128(&dfu_uuid(3)),
+            Attribute::new([0u8; 5]).security(security),
+            Metadata::with_security(Properties::new().notify(), security),
+        )?;
+        let status_handle = status.build();
+
+        let _service_handle = service_builder.build();
+
+        Ok(DfuService {
+            control: control_handle.value_handle,
+            packet: packet_handle.value_handle,
+            status: status_handle.value_handle,
+        })
+    }
+
+    // The status and the offset written so far, as little endian [u8, u32].
+    // The host waits for each one, so a full TX queue delays it instead of dropping it.
+    async fn notify_status(&self, status: DfuStatus, offset: u32, connections: &Connections) {
+        let mut value = [status as u8, 0, 0, 0, 0];
+        value[1..5].copy_from_slice(&offset.to_le_bytes());
+        let mut notified = [false; MAX_CONNECTIONS];
+        loop {
+            let mut queue_full = false;
+            connections.for_each_slot(|slot, connection| {
+                if notified[slot] {
+                    return;
+                }
+                match gatt_server::notify_value(connection, self.status, &value) {
+                    Err(NotifyValueError::Raw(RawError::Resources)) => queue_full = true,
+                    result => {
+                        notified[slot] = true;
+                        unwrap_notify(result, "dfu");
+                    }
+                }
+            });
+            if !queue_full {
+                return;
+            }
+            Timer::after(STATUS_RETRY).await;
+        }
+    }
+
+    // Flash writes are slow, so the commands are queued to the DFU task
+    pub fn on_write(
+        &self,
+        event: DfuServiceEvent,
+        commands: &Channel<NoopRawMutex, DfuServiceEvent, DFU_WINDOW>,
+    ) {
+        if commands.try_send(event).is_err() {
+            warn!("dfu: too many chunks in flight");
+        }
+    }
+}
+
+impl gatt_server::Service for DfuService {
+    type Event = DfuServiceEvent;
+
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        if handle == self.control {
+            return match data {
+                [START, size @ ..] if size.len() == 4 => Some(DfuServiceEvent::Start {
+                    size: u32::from_le_bytes([size[0], size[1], size[2], size[3]]),
+                }),
+                [FINISH, signature @ ..] if signature.len() == SIGNATURE_SIZE => {
+                    let mut buf = [0u8; SIGNATURE_SIZE];
+                    buf.copy_from_slice(signature);
+                    Some(DfuServiceEvent::Finish { signature: buf })
+                }
+                [ABORT] => Some(DfuServiceEvent::Abort),
+                _ => {
+                    warn!("dfu: invalid control {=[u8]:x}", data);
+                    None
+                }
+            };
+        }
+
+        if handle == self.packet && data.len() > 4 {
+            let len = data.len() - 4;
+            let mut chunk = AlignedBuffer([0u8; CHUNK_MAX_SIZE]);
+            chunk.0[..len].copy_from_slice(&data[4..]);
+            return Some(DfuServiceEvent::Chunk {
+                offset: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                len,
+                data: chunk,
+            });
+        }
+
+        None
+    }
+}
+
+// Confirms the running image, otherwise the bootloader swaps back to the
+// previous one on the next reset. Called once booted without errors.
+pub async fn mark_booted(flash: &Mutex<NoopRawMutex, Flash>) {
+    let config = FirmwareUpdaterConfig::from_linkerfile(flash, flash);
+    let mut aligned = AlignedBuffer([0u8; 4]);
+    let mut updater = FirmwareUpdater::new(config, &mut aligned.0);
+    if let Err(e) = updater.mark_booted().await {
+        warn!("could not mark booted: {:?}", e);
+    }
+}
+
+// Checks the signature of the received image and asks the bootloader to swap it in
+async fn verify(
+    flash: &Mutex<NoopRawMutex, Flash>,
+    signature: &[u8; SIGNATURE_SIZE],
+    size: u32,
+) -> bool {
+    let config = FirmwareUpdaterConfig::from_linkerfile(flash, flash);
+    let mut aligned = AlignedBuffer([0u8; 4]);
+    let mut updater = FirmwareUpdater::new(config, &mut aligned.0);
+    match updater
+        .verify_and_mark_updated(PUBLIC_KEY, signature, size)
+        .await
+    {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("dfu: invalid image: {:?}", e);
+            false
+        }
+    }
+}
+
+// Receive an image into the DFU partition. The chunks must come in order and
+// word aligned, any error fails the update until it starts over.
+pub async fn dfu_task<'a>(
+    flash: &Mutex<NoopRawMutex, Flash>,
+    server: &'a Server,
+    connections: &'a Connections,
+    commands: &Channel<NoopRawMutex, DfuServiceEvent, DFU_WINDOW>,
+) {
+    let mut size = None;
+    let mut received = 0;
+    loop {
+        let status = match (commands.receive().await, size) {
+            (DfuServiceEvent::Start { size: new_size }, _) => {
+                info!("dfu: receiving {} bytes", new_size);
+                received = 0;
+                size = None;
+                if new_size == 0 || new_size > max_image_size() || new_size % 4 != 0 {
+                    warn!("dfu: invalid size {}", new_size);
+                    DfuStatus::Failed
+                } else {
+                    let pages = new_size.div_ceil(Flash::ERASE_SIZE as u32);
+                    let end = dfu_address() + pages * Flash::ERASE_SIZE as u32;
+                    match flash.lock().await.erase(dfu_address(), end).await {
+                        Ok(()) => {
+                            size = Some(new_size);
+                            DfuStatus::Receiving
+                        }
+                        Err(e) => {
+                            warn!("dfu: could not erase: {:?}", e);
+                            DfuStatus::Failed
+                        }
+                    }
+                }
+            }
+            (DfuServiceEvent::Chunk { offset, len, data }, Some(size)) => {
+                if offset != received || len % 4 != 0 || offset + len as u32 > size {
+                    warn!("dfu: unexpected chunk at {} of {} bytes", offset, len);
+                    DfuStatus::Failed
+                } else {
+                    let address = dfu_address() + offset;
+                    match flash.lock().await.write(address, &data.0[..len]).await {
+                        Ok(()) => {
+                            received += len as u32;
+                            DfuStatus::Receiving
+                        }
+                        Err(e) => {
+                            warn!("dfu: could not write: {:?}", e);
+                            DfuStatus::Failed
+                        }
+                    }
+                }
+            }
+            (DfuServiceEvent::Finish { signature }, Some(size)) if received == size => {
+                if verify(flash, &signature, size).await {
+                    DfuStatus::Verified
+                } else {
+                    DfuStatus::Failed
+                }
+            }
+            (DfuServiceEvent::Abort, _) => {
+                info!("dfu: aborted");
+                DfuStatus::Idle
+            }
+            _ => {
+                warn!("dfu: not receiving an image");
+                DfuStatus::Failed
+            }
+        };
+
+        if status != DfuStatus::Receiving {
+            size = None;
+        }
+        server
+            .dfu
+            .notify_status(status, received, connections)
+            .await;
+
+        if status == DfuStatus::Verified {
+            info!("dfu: image verified, rebooting");
+            Timer::after_millis(500).await; // lets the notification go out
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use defmt::*;
+use embassy_nrf::pac;
+use embassy_time::{Duration, Timer};
+
+use crate::ble::Connections;
+use crate::bus::Bus;
+use crate::crash::{CrashReport, CRASH_REPORT_LEN};
+use crate::link::{LinkReport, LINK_REPORT_LEN};
+use crate::{unwrap_notify, Server};
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// GATT Service
+#[nrf_softdevice::gatt_service(uuid = "0000DAD7-0000-0000-0000-000000000000")]
+pub struct DiagnosticsService {
+    #[characteristic(uuid = "0000DAD7-0000-0000-0000-000000000001", read, notify)]
+    i2c_errors: [u8; 8], // u32 errors and u32 bus recoveries since boot, little endian
+    #[characteristic(uuid = "0000DAD7-0000-0000-0000-000000000002", read)]
+    reset_reason: u8,
+    #[characteristic(uuid = "0000DAD7-0000-0000-0000-000000000003", read)]
+    crash_report: [u8; CRASH_REPORT_LEN], // all zero when the last reset wasn't a crash
+    #[characteristic(
+        uuid = "0000DAD7-0000-0000-0000-000000000005",
+        write,
+        security = "just_works"
+    )]
+    crash_ack: u8, // any write clears the crash report
+    #[characteristic(uuid = "0000DAD7-0000-0000-0000-000000000004", read)]
+    link: [u8; LINK_REPORT_LEN], // of the connection whose link changed last
+}
+
+// Cause of the last reset
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+#[repr(u8)]
+pub enum ResetReason {
+    PowerOn = 0, // or brownout, which aren't recorded
+    Pin = 1,
+    Watchdog = 2,
+    Soft = 3, // e.g. rebooting into a firmware update
+    Lockup = 4,
+    Wake = 5, // from System OFF
+    Other = 6,
+}
+
+impl ResetReason {
+    // RESETREAS accumulates the causes until cleared, so it's cleared once read.
+    // Read before the softdevice is enabled, POWER then belongs to it.
+    pub fn take() -> Self {
+        let power = unsafe { &*pac::POWER::ptr() };
+        let reasons = power.resetreas.read();
+        let reason = if reasons.dog().is_detected() {
+            ResetReason::Watchdog
+        } else if reasons.lockup().is_detected() {
+            ResetReason::Lockup
+        } else if reasons.sreq().is_detected() {
+            ResetReason::Soft
+        } else if reasons.resetpin().is_detected() {
+            ResetReason::Pin
+        } else if reasons.off().is_detected() {
+            ResetReason::Wake
+        } else if reasons.bits() != 0 {
+            ResetReason::Other
+        } else {
+            ResetReason::PowerOn
+        };
+        power.resetreas.write(|w| unsafe { w.bits(reasons.bits()) });
+        reason
+    }
+}
+
+impl DiagnosticsService {
+    pub fn set_reset_reason(&self, reason: ResetReason) {
+        match reason {
+            ResetReason::Watchdog | ResetReason::Lockup => warn!("reset reason: {:?}", reason),
+            _ => info!("reset reason: {:?}", reason),
+        }
+        unwrap!(self.reset_reason_set(&(reason as u8)));
+    }
+
+    pub fn set_crash_report(&self, report: Option<CrashReport>) {
+        if let Some(report) = &report {
+            warn!("crash report not acknowledged yet: {}", report);
+        }
+        let report = report.unwrap_or(CrashReport::empty());
+        unwrap!(self.crash_report_set(&report.to_bytes()));
+    }
+
+    // The report was read, the next reset only reports a new crash
+    pub fn on_write(&self, event: DiagnosticsServiceEvent) {
+        match event {
+            DiagnosticsServiceEvent::CrashAckWrite(_) => {
+                info!("crash report acknowledged");
+                CrashReport::clear();
+                self.set_crash_report(None);
+            }
+            DiagnosticsServiceEvent::I2cErrorsCccdWrite { notifications } => {
+                info!("i2c errors notifications: {}", notifications)
+            }
+        }
+    }
+
+    pub fn set_link(&self, report: &LinkReport) {
+        info!("link: {:?}", report);
+        unwrap!(self.link_set(&report.to_bytes()));
+    }
+}
+
+// Update the counters and notify when they change
+pub async fn diagnostics_task<'a>(bus: &Bus, server: &'a Server, connections: &'a Connections) {
+    let mut previous = None;
+    loop {
+        let counters = bus.counters();
+        if previous != Some(counters) {
+            unwrap!(server.diagnostics.i2c_errors_set(&counters));
+            connections.for_each(|connection| {
+                unwrap_notify(
+                    server.diagnostics.i2c_errors_notify(connection, &counters),
+                    "i2c_errors",
+                )
+            });
+            previous = Some(counters);
+        }
+        Timer::after(UPDATE_INTERVAL).await;
+    }
+}
@@ -0,0 +1,46 @@
+// Inputs of the gesture model. gesture-trainer includes this file, so the
+// recordings it learns from are turned into the very same features.
+use libm::{atan2f, remainderf, sqrtf};
+
+use crate::fusion::Vector3;
+
+pub const FEATURE_COUNT: usize = 9;
+
+// Pitch and roll in radians
+// Tilt comes from the fused gravity direction, so jerks (e.g. jump) don't move the D-pad
+pub fn tilt(gravity: Vector3) -> (f32, f32) {
+    let pitch = atan2f(
+        gravity.0,
+        sqrtf(gravity.1 * gravity.1 + gravity.2 * gravity.2),
+    );
+    let roll = atan2f(
+        gravity.1,
+        sqrtf(gravity.0 * gravity.0 + gravity.2 * gravity.2),
+    );
+    (pitch, roll)
+}
+
+// The AK8963 axes don't match the accelerometer and gyroscope ones:
+// x and y are swapped and z points the other way.
+pub fn align_mag(mag: Vector3) -> Vector3 {
+    (mag.1, mag.0, -mag.2)
+}
+
+// Angle in radians wrapped to [-π, π]
+pub fn wrap_angle(angle: f32) -> f32 {
+    remainderf(angle, 2.0 * core::f32::consts::PI)
+}
+
+// Pitch, roll and heading in rad, then the accelerometer (m/s²)
+// and the gyroscope (rad/s) x, y and z
+pub fn features(
+    gravity: Vector3,
+    heading: f32,
+    accel: Vector3,
+    gyro: Vector3,
+) -> [f32; FEATURE_COUNT] {
+    let (pitch, roll) = tilt(gravity);
+    [
+        pitch, roll, heading, accel.0, accel.1, accel.2, gyro.0, gyro.1, gyro.2,
+    ]
+}
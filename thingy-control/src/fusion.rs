@@ -0,0 +1,425 @@
+// Madgwick orientation filter, fuses the gyroscope integration with the
+// accelerometer (and optionally magnetometer) reference by gradient descent.
+// based on: https://x-io.co.uk/open-source-imu-and-ahrs-algorithms/
+use libm::{atan2f, cosf, sinf, sqrtf};
+
+pub type Vector3 = (f32, f32, f32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Default for Quaternion {
+    fn default() -> Self {
+        Quaternion {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+}
+
+impl Quaternion {
+    // Orientation that aligns the earth vertical with the measured acceleration, heading is zero
+    pub fn from_accel(accel: Vector3) -> Self {
+        let (ax, ay, az) = accel;
+        let roll = atan2f(ay, az);
+        let pitch = atan2f(-ax, sqrtf(ay * ay + az * az));
+
+        let (sr, cr) = (sinf(roll / 2.0), cosf(roll / 2.0));
+        let (sp, cp) = (sinf(pitch / 2.0), cosf(pitch / 2.0));
+        Quaternion {
+            w: cr * cp,
+            x: sr * cp,
+            y: cr * sp,
+            z: -sr * sp,
+        }
+    }
+
+    // Same as `from_accel`, with the heading that points the sensor x axis
+    // to the magnetic north, tilt compensated
+    pub fn from_accel_mag(accel: Vector3, mag: Vector3) -> Self {
+        let q = Quaternion::from_accel(accel);
+        let (mx, my, _) = q.to_earth(mag);
+        let half = -atan2f(my, mx) / 2.0;
+        Quaternion {
+            w: cosf(half),
+            x: 0.0,
+            y: 0.0,
+            z: sinf(half),
+        }
+        .mul(q)
+    }
+
+    // Hamilton product, `a.mul(b)` rotates by `b` then by `a`
+    fn mul(self, other: Self) -> Self {
+        let Quaternion { w, x, y, z } = self;
+        let Quaternion {
+            w: ow,
+            x: ox,
+            y: oy,
+            z: oz,
+        } = other;
+        Quaternion {
+            w: w * ow - x * ox - y * oy - z * oz,
+            x: w * ox + x * ow + y * oz - z * oy,
+            y: w * oy - x * oz + y * ow + z * ox,
+            z: w * oz + x * oy - y * ox + z * ow,
+        }
+    }
+
+    // Sensor frame vector in the earth frame
+    fn to_earth(&self, v: Vector3) -> Vector3 {
+        let Quaternion { w, x, y, z } = *self;
+        (
+            (1.0 - 2.0 * (y * y + z * z)) * v.0
+                + 2.0 * (x * y - w * z) * v.1
+                + 2.0 * (x * z + w * y) * v.2,
+            2.0 * (x * y + w * z) * v.0
+                + (1.0 - 2.0 * (x * x + z * z)) * v.1
+                + 2.0 * (y * z - w * x) * v.2,
+            2.0 * (x * z - w * y) * v.0
+                + 2.0 * (y * z + w * x) * v.1
+                + (1.0 - 2.0 * (x * x + y * y)) * v.2,
+        )
+    }
+
+    // Direction of the earth vertical in the sensor frame, with the same sign
+    // as the accelerometer reading at rest
+    pub fn gravity(&self) -> Vector3 {
+        let Quaternion { w, x, y, z } = *self;
+        (
+            2.0 * (x * z - w * y),
+            2.0 * (w * x + y * z),
+            w * w - x * x - y * y + z * z,
+        )
+    }
+
+    // Rotation around the earth vertical, in radians
+    pub fn yaw(&self) -> f32 {
+        let Quaternion { w, x, y, z } = *self;
+        atan2f(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z))
+    }
+
+    fn normalized(self) -> Self {
+        let norm = sqrtf(self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z);
+        Quaternion {
+            w: self.w / norm,
+            x: self.x / norm,
+            y: self.y / norm,
+            z: self.z / norm,
+        }
+    }
+}
+
+fn normalize(v: Vector3) -> Option<Vector3> {
+    let norm = sqrtf(v.0 * v.0 + v.1 * v.1 + v.2 * v.2);
+    if norm == 0.0 {
+        return None;
+    }
+    Some((v.0 / norm, v.1 / norm, v.2 / norm))
+}
+
+pub struct Madgwick {
+    q: Option<Quaternion>,
+    sample_period: f32,
+    beta: f32,
+}
+
+impl Madgwick {
+    // `sample_period` in seconds, `beta` is the gain of the accelerometer
+    // correction: higher converges faster but lets more linear motion through.
+    pub const fn new(sample_period: f32, beta: f32) -> Self {
+        Madgwick {
+            q: None,
+            sample_period,
+            beta,
+        }
+    }
+
+    pub fn quaternion(&self) -> Quaternion {
+        self.q.unwrap_or_default()
+    }
+
+    // The gradient is zero when the estimate is upside down, so the first
+    // sample sets the orientation directly instead of converging from identity.
+    // With the magnetometer the heading starts right too.
+    fn initialize(&mut self, accel: Vector3, mag: Option<Vector3>) -> Option<Quaternion> {
+        match self.q {
+            Some(q) => Some(q),
+            None => {
+                self.q = normalize(accel).map(|accel| match mag {
+                    Some(mag) => Quaternion::from_accel_mag(accel, mag),
+                    None => Quaternion::from_accel(accel),
+                });
+                None
+            }
+        }
+    }
+
+    // Integrate the quaternion rate minus the normalized correction step
+    fn integrate(&mut self, q: Quaternion, gyro: Vector3, step: (f32, f32, f32, f32)) {
+        let (gx, gy, gz) = gyro;
+        let Quaternion { w, x, y, z } = q;
+        let (s0, s1, s2, s3) = step;
+        let step_norm = sqrtf(s0 * s0 + s1 * s1 + s2 * s2 + s3 * s3);
+        let (s0, s1, s2, s3) = if step_norm > 0.0 {
+            (
+                s0 / step_norm,
+                s1 / step_norm,
+                s2 / step_norm,
+                s3 / step_norm,
+            )
+        } else {
+            (0.0, 0.0, 0.0, 0.0)
+        };
+
+        let dw = 0.5 * (-x * gx - y * gy - z * gz) - self.beta * s0;
+        let dx = 0.5 * (w * gx + y * gz - z * gy) - self.beta * s1;
+        let dy = 0.5 * (w * gy - x * gz + z * gx) - self.beta * s2;
+        let dz = 0.5 * (w * gz + x * gy - y * gx) - self.beta * s3;
+
+        let dt = self.sample_period;
+        self.q = Some(
+            Quaternion {
+                w: w + dw * dt,
+                x: x + dx * dt,
+                y: y + dy * dt,
+                z: z + dz * dt,
+            }
+            .normalized(),
+        );
+    }
+
+    // Gyroscope in rad/s, accelerometer in any unit
+    pub fn update_imu(&mut self, gyro: Vector3, accel: Vector3) {
+        let Some(q) = self.initialize(accel, None) else {
+            return;
+        };
+        let Some((ax, ay, az)) = normalize(accel) else {
+            return self.integrate(q, gyro, (0.0, 0.0, 0.0, 0.0));
+        };
+
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = q;
+        let (q0q0, q1q1, q2q2, q3q3) = (q0 * q0, q1 * q1, q2 * q2, q3 * q3);
+
+        let s0 = 4.0 * q0 * q2q2 + 2.0 * q2 * ax + 4.0 * q0 * q1q1 - 2.0 * q1 * ay;
+        let s1 = 4.0 * q1 * q3q3 - 2.0 * q3 * ax + 4.0 * q0q0 * q1 - 2.0 * q0 * ay - 4.0 * q1
+            + 8.0 * q1 * q1q1
+            + 8.0 * q1 * q2q2
+            + 4.0 * q1 * az;
+        let s2 = 4.0 * q0q0 * q2 + 2.0 * q0 * ax + 4.0 * q2 * q3q3 - 2.0 * q3 * ay - 4.0 * q2
+            + 8.0 * q2 * q1q1
+            + 8.0 * q2 * q2q2
+            + 4.0 * q2 * az;
+        let s3 = 4.0 * q1q1 * q3 - 2.0 * q1 * ax + 4.0 * q2q2 * q3 - 2.0 * q2 * ay;
+
+        self.integrate(q, gyro, (s0, s1, s2, s3));
+    }
+
+    // Same as `update_imu` but also corrects the heading with the magnetometer,
+    // falls back to `update_imu` when there is no magnetometer reading.
+    pub fn update_marg(&mut self, gyro: Vector3, accel: Vector3, mag: Vector3) {
+        let Some((mx, my, mz)) = normalize(mag) else {
+            return self.update_imu(gyro, accel);
+        };
+        let Some(q) = self.initialize(accel, Some((mx, my, mz))) else {
+            return;
+        };
+        let Some((ax, ay, az)) = normalize(accel) else {
+            return self.integrate(q, gyro, (0.0, 0.0, 0.0, 0.0));
+        };
+
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = q;
+        let (q0q0, q0q1, q0q2, q0q3) = (q0 * q0, q0 * q1, q0 * q2, q0 * q3);
+        let (q1q1, q1q2, q1q3) = (q1 * q1, q1 * q2, q1 * q3);
+        let (q2q2, q2q3, q3q3) = (q2 * q2, q2 * q3, q3 * q3);
+
+        // Reference direction of the earth magnetic field
+        let hx = mx * q0q0 - 2.0 * q0 * my * q3
+            + 2.0 * q0 * mz * q2
+            + mx * q1q1
+            + 2.0 * q1 * my * q2
+            + 2.0 * q1 * mz * q3
+            - mx * q2q2
+            - mx * q3q3;
+        let hy = 2.0 * q0 * mx * q3 + my * q0q0 - 2.0 * q0 * mz * q1 + 2.0 * q1 * mx * q2
+            - my * q1q1
+            + my * q2q2
+            + 2.0 * q2 * mz * q3
+            - my * q3q3;
+        let bx = sqrtf(hx * hx + hy * hy) / 2.0;
+        let bz = (-2.0 * q0 * mx * q2 + 2.0 * q0 * my * q1 + mz * q0q0 + 2.0 * q1 * mx * q3
+            - mz * q1q1
+            + 2.0 * q2 * my * q3
+            - mz * q2q2
+            + mz * q3q3)
+            / 2.0;
+
+        // Objective function, estimated minus measured directions
+        let fax = 2.0 * (q1q3 - q0q2) - ax;
+        let fay = 2.0 * (q0q1 + q2q3) - ay;
+        let faz = 1.0 - 2.0 * (q1q1 + q2q2) - az;
+        let fmx = 2.0 * bx * (0.5 - q2q2 - q3q3) + 2.0 * bz * (q1q3 - q0q2) - mx;
+        let fmy = 2.0 * bx * (q1q2 - q0q3) + 2.0 * bz * (q0q1 + q2q3) - my;
+        let fmz = 2.0 * bx * (q0q2 + q1q3) + 2.0 * bz * (0.5 - q1q1 - q2q2) - mz;
+
+        // Gradient, jacobian transposed times the objective function
+        let s0 = -2.0 * q2 * fax + 2.0 * q1 * fay - 2.0 * bz * q2 * fmx
+            + (-2.0 * bx * q3 + 2.0 * bz * q1) * fmy
+            + 2.0 * bx * q2 * fmz;
+        let s1 = 2.0 * q3 * fax + 2.0 * q0 * fay - 4.0 * q1 * faz
+            + 2.0 * bz * q3 * fmx
+            + (2.0 * bx * q2 + 2.0 * bz * q0) * fmy
+            + (2.0 * bx * q3 - 4.0 * bz * q1) * fmz;
+        let s2 = -2.0 * q0 * fax + 2.0 * q3 * fay - 4.0 * q2 * faz
+            + (-4.0 * bx * q2 - 2.0 * bz * q0) * fmx
+            + (2.0 * bx * q1 + 2.0 * bz * q3) * fmy
+            + (2.0 * bx * q0 - 4.0 * bz * q2) * fmz;
+        let s3 = 2.0 * q1 * fax
+            + 2.0 * q2 * fay
+            + (-4.0 * bx * q3 + 2.0 * bz * q1) * fmx
+            + (-2.0 * bx * q0 + 2.0 * bz * q2) * fmy
+            + 2.0 * bx * q1 * fmz;
+
+        self.integrate(q, gyro, (s0, s1, s2, s3));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_PERIOD: f32 = 0.005; // 200 Hz
+    const BETA: f32 = 0.1;
+    const LEVEL: Vector3 = (0.0, 0.0, 9.81);
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    // Same rotation, q and -q included
+    fn assert_same_rotation(actual: Quaternion, expected: Quaternion, tolerance: f32) {
+        let dot = actual.w * expected.w
+            + actual.x * expected.x
+            + actual.y * expected.y
+            + actual.z * expected.z;
+        assert_close(dot.abs(), 1.0, tolerance);
+    }
+
+    // Held still, rolled by `angle` around x
+    fn rolled(angle: f32) -> Vector3 {
+        (0.0, 9.81 * sinf(angle), 9.81 * cosf(angle))
+    }
+
+    // Rolled by `roll` around x, then turned by `heading` around the vertical
+    fn orientation(roll: f32, heading: f32) -> Quaternion {
+        let turn = Quaternion {
+            w: cosf(heading / 2.0),
+            x: 0.0,
+            y: 0.0,
+            z: sinf(heading / 2.0),
+        };
+        let tilt = Quaternion {
+            w: cosf(roll / 2.0),
+            x: sinf(roll / 2.0),
+            y: 0.0,
+            z: 0.0,
+        };
+        turn.mul(tilt)
+    }
+
+    // Earth frame vector as the sensor sees it
+    fn seen_by(q: Quaternion, v: Vector3) -> Vector3 {
+        let inverse = Quaternion {
+            x: -q.x,
+            y: -q.y,
+            z: -q.z,
+            ..q
+        };
+        inverse.to_earth(v)
+    }
+
+    // Earth field pointing north with a downward dip
+    const EARTH_FIELD: Vector3 = (0.5, 0.0, -0.8);
+
+    #[test]
+    fn first_sample_sets_the_tilt() {
+        let mut filter = Madgwick::new(SAMPLE_PERIOD, BETA);
+        filter.update_imu((0.0, 0.0, 0.0), rolled(0.5));
+        assert_same_rotation(
+            filter.quaternion(),
+            Quaternion::from_accel(rolled(0.5)),
+            1e-6,
+        );
+    }
+
+    #[test]
+    fn static_tilt_converges_to_the_accelerometer() {
+        let mut filter = Madgwick::new(SAMPLE_PERIOD, BETA);
+        filter.update_imu((0.0, 0.0, 0.0), LEVEL);
+        // Tilted without the gyroscope seeing it, only the correction moves the estimate
+        for _ in 0..4000 {
+            filter.update_imu((0.0, 0.0, 0.0), rolled(0.5));
+        }
+        let gravity = filter.quaternion().gravity();
+        let (_, ay, az) = rolled(0.5);
+        assert_close(gravity.0, 0.0, 0.01);
+        assert_close(gravity.1, ay / 9.81, 0.01);
+        assert_close(gravity.2, az / 9.81, 0.01);
+    }
+
+    #[test]
+    fn constant_yaw_rate_integrates_the_heading() {
+        let mut filter = Madgwick::new(SAMPLE_PERIOD, BETA);
+        filter.update_imu((0.0, 0.0, 0.0), LEVEL);
+        // 1 s at 1 rad/s, the accelerometer can't correct the heading
+        for _ in 0..200 {
+            filter.update_imu((0.0, 0.0, 1.0), LEVEL);
+        }
+        let q = filter.quaternion();
+        assert_close(q.yaw(), 1.0, 0.01);
+        assert_close(q.gravity().2, 1.0, 1e-3);
+    }
+
+    #[test]
+    fn marg_heading_converges_to_the_magnetometer() {
+        let start = orientation(0.2, 0.0);
+        let end = orientation(0.2, 0.5);
+        let mut filter = Madgwick::new(SAMPLE_PERIOD, BETA);
+        filter.update_marg(
+            (0.0, 0.0, 0.0),
+            seen_by(start, LEVEL),
+            seen_by(start, EARTH_FIELD),
+        );
+        assert_same_rotation(filter.quaternion(), start, 1e-5);
+        // Turned without the gyroscope seeing it, the magnetometer pulls the heading over
+        for _ in 0..4000 {
+            filter.update_marg(
+                (0.0, 0.0, 0.0),
+                seen_by(end, LEVEL),
+                seen_by(end, EARTH_FIELD),
+            );
+        }
+        assert_same_rotation(filter.quaternion(), end, 1e-4);
+        assert_close(filter.quaternion().yaw(), 0.5, 0.01);
+    }
+}
@@ -0,0 +1,353 @@
+// Hysteresis and debounce for the classifier decisions, so a value hovering
+// around a threshold doesn't toggle the output on every sample.
+// The button actions are debounced by the button task.
+use defmt::Format;
+
+use crate::{Control, LeftRight, UpDown};
+
+// Longest hold time a central may set, the gesture would feel stuck beyond it
+const MAX_HOLD_MS: u32 = 1000;
+
+// Per gesture tuning
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Tuning {
+    pub band: f32,    // hysteresis, in the unit of the gesture threshold
+    pub hold_ms: u32, // minimum time between two changes
+}
+
+impl Tuning {
+    // The gesture turns off a band below its threshold, which must stay above the value
+    // at rest (0 for the angles and rates, gravity for the jump) or it never turns off
+    pub fn is_valid(&self, threshold: f32, rest: f32) -> bool {
+        self.band >= 0.0 && threshold - self.band > rest && self.hold_ms <= MAX_HOLD_MS
+    }
+}
+
+// Part of the settings, so a central can tune it
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct GestureTuning {
+    pub left_right: Tuning, // rad
+    pub up_down: Tuning,    // rad
+    pub jump: Tuning,       // m/s²
+    pub spin: Tuning,       // rad/s
+    pub turn: Tuning,       // rad
+}
+
+pub const GESTURE_TUNING_LEN: usize = 5 * 8;
+
+impl Default for GestureTuning {
+    fn default() -> Self {
+        GestureTuning {
+            left_right: Tuning {
+                band: 0.1,
+                hold_ms: 60,
+            },
+            up_down: Tuning {
+                band: 0.1,
+                hold_ms: 60,
+            },
+            jump: Tuning {
+                band: 1.5,
+                hold_ms: 150,
+            },
+            spin: Tuning {
+                band: 1.0,
+                hold_ms: 150,
+            },
+            turn: Tuning {
+                band: 0.1,
+                hold_ms: 150,
+            },
+        }
+    }
+}
+
+impl GestureTuning {
+    // Little endian [band f32, hold_ms u32] of left_right, up_down, jump, spin and turn
+    pub fn to_bytes(self) -> [u8; GESTURE_TUNING_LEN] {
+        let mut buf = [0u8; GESTURE_TUNING_LEN];
+        let gestures = [
+            self.left_right,
+            self.up_down,
+            self.jump,
+            self.spin,
+            self.turn,
+        ];
+        for (chunk, tuning) in buf.chunks_exact_mut(8).zip(gestures) {
+            chunk[0..4].copy_from_slice(&tuning.band.to_le_bytes());
+            chunk[4..8].copy_from_slice(&tuning.hold_ms.to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; GESTURE_TUNING_LEN]) -> Self {
+        let tuning = |i: usize| {
+            let word = |j: usize| [buf[j], buf[j + 1], buf[j + 2], buf[j + 3]];
+            Tuning {
+                band: f32::from_le_bytes(word(8 * i)),
+                hold_ms: u32::from_le_bytes(word(8 * i + 4)),
+            }
+        };
+        GestureTuning {
+            left_right: tuning(0),
+            up_down: tuning(1),
+            jump: tuning(2),
+            spin: tuning(3),
+            turn: tuning(4),
+        }
+    }
+}
+
+// Schmitt trigger: turns on above the threshold,
+// but once on it only turns off a band below it.
+pub fn above(value: f32, threshold: f32, band: f32, active: bool) -> bool {
+    if active {
+        value > threshold - band
+    } else {
+        value > threshold
+    }
+}
+
+// Reports a change immediately, then holds it for at least `hold_ms`
+pub struct Debouncer<T> {
+    state: T,
+    changed_at: u32,
+}
+
+impl<T: Copy + PartialEq> Debouncer<T> {
+    pub const fn new(initial: T) -> Self {
+        Debouncer {
+            state: initial,
+            changed_at: 0,
+        }
+    }
+
+    pub fn update(&mut self, value: T, now_ms: u32, hold_ms: u32) -> T {
+        if value != self.state && now_ms.wrapping_sub(self.changed_at) >= hold_ms {
+            self.state = value;
+            self.changed_at = now_ms;
+        }
+        self.state
+    }
+}
+
+// The hold times come with every update, a central may change them at any time
+pub struct ControlDebouncer {
+    left_right: Debouncer<LeftRight>,
+    up_down: Debouncer<UpDown>,
+    jump: Debouncer<bool>,
+    spin: Debouncer<bool>,
+    turn: Debouncer<LeftRight>,
+}
+
+impl ControlDebouncer {
+    pub const fn new() -> Self {
+        ControlDebouncer {
+            left_right: Debouncer::new(LeftRight::None),
+            up_down: Debouncer::new(UpDown::None),
+            jump: Debouncer::new(false),
+            spin: Debouncer::new(false),
+            turn: Debouncer::new(LeftRight::None),
+        }
+    }
+
+    pub fn update(&mut self, control: Control, now_ms: u32, tuning: &GestureTuning) -> Control {
+        Control {
+            left_right: self.left_right.update(
+                control.left_right,
+                now_ms,
+                tuning.left_right.hold_ms,
+            ),
+            up_down: self
+                .up_down
+                .update(control.up_down, now_ms, tuning.up_down.hold_ms),
+            jump: self.jump.update(control.jump, now_ms, tuning.jump.hold_ms),
+            spin: self.spin.update(control.spin, now_ms, tuning.spin.hold_ms),
+            turn: self.turn.update(control.turn, now_ms, tuning.turn.hold_ms),
+            ..control
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TUNING: Tuning = Tuning {
+        band: 0.1,
+        hold_ms: 60,
+    };
+    const GESTURE_TUNING: GestureTuning = GestureTuning {
+        left_right: TUNING,
+        up_down: TUNING,
+        jump: TUNING,
+        spin: TUNING,
+        turn: TUNING,
+    };
+    const THRESHOLD: f32 = 0.3;
+    const SAMPLE_PERIOD_MS: u32 = 5;
+
+    // Deterministic noise, uniform in [-amplitude, amplitude]
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self, amplitude: f32) -> f32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            ((self.0 >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * amplitude
+        }
+    }
+
+    // Rests at 0, hovers right on the threshold for 1 s, then rests again
+    fn hovering(amplitude: f32) -> Vec<f32> {
+        let mut noise = Noise(1);
+        [(0.0, 50), (THRESHOLD, 200), (0.0, 50)]
+            .into_iter()
+            .flat_map(|(value, samples)| core::iter::repeat_n(value, samples))
+            .map(|value| value + noise.next(amplitude))
+            .collect()
+    }
+
+    fn schmitt(trace: &[f32], band: f32) -> Vec<bool> {
+        let mut active = false;
+        trace
+            .iter()
+            .map(|&value| {
+                active = above(value, THRESHOLD, band, active);
+                active
+            })
+            .collect()
+    }
+
+    fn transitions<T: PartialEq>(initial: T, outputs: impl IntoIterator<Item = T>) -> usize {
+        let mut previous = initial;
+        let mut count = 0;
+        for output in outputs {
+            if output != previous {
+                count += 1;
+            }
+            previous = output;
+        }
+        count
+    }
+
+    #[test]
+    fn noise_toggles_a_bare_threshold() {
+        let trace = hovering(0.05);
+        assert!(transitions(false, schmitt(&trace, 0.0)) > 20);
+    }
+
+    #[test]
+    fn hysteresis_ignores_noise_within_the_band() {
+        let trace = hovering(0.05);
+        // On once the threshold is first crossed, off once back at rest
+        assert_eq!(transitions(false, schmitt(&trace, TUNING.band)), 2);
+    }
+
+    #[test]
+    fn debouncer_holds_each_change() {
+        let mut noise = Noise(2);
+        let mut debouncer = Debouncer::new(false);
+        // A flickering input for 1 s, at most one change per hold time goes through
+        let outputs: Vec<bool> = (0..200)
+            .map(|i| {
+                let now_ms = 1000 + i * SAMPLE_PERIOD_MS;
+                debouncer.update(noise.next(1.0) > 0.0, now_ms, TUNING.hold_ms)
+            })
+            .collect();
+        let count = transitions(false, outputs);
+        assert!(count > 1);
+        assert!(count <= (200 * SAMPLE_PERIOD_MS / TUNING.hold_ms + 1) as usize);
+    }
+
+    #[test]
+    fn debouncer_reports_a_change_at_once() {
+        let mut debouncer = Debouncer::new(false);
+        let hold_ms = TUNING.hold_ms;
+        assert!(debouncer.update(true, 1000, hold_ms));
+        assert!(debouncer.update(false, 1000 + hold_ms - 1, hold_ms));
+        assert!(!debouncer.update(false, 1000 + hold_ms, hold_ms));
+    }
+
+    #[test]
+    fn tuning_bytes_round_trip() {
+        let tuning = GestureTuning::default();
+        assert_eq!(GestureTuning::from_bytes(&tuning.to_bytes()), tuning);
+        assert_eq!(tuning.to_bytes()[16..24], [0, 0, 0xc0, 0x3f, 150, 0, 0, 0]);
+    }
+
+    #[test]
+    fn band_must_be_narrower_than_the_threshold() {
+        assert!(TUNING.is_valid(0.3, 0.0));
+        assert!(!TUNING.is_valid(0.1, 0.0));
+        assert!(!Tuning {
+            band: -0.1,
+            ..TUNING
+        }
+        .is_valid(0.3, 0.0));
+        assert!(!Tuning {
+            band: f32::NAN,
+            ..TUNING
+        }
+        .is_valid(0.3, 0.0));
+        assert!(!Tuning {
+            hold_ms: 5000,
+            ..TUNING
+        }
+        .is_valid(0.3, 0.0));
+    }
+
+    #[test]
+    fn jump_must_turn_off_at_rest() {
+        let rest = -9.81;
+        let jump = |band| Tuning { band, hold_ms: 150 };
+        assert!(jump(1.5).is_valid(-6.5, rest));
+        assert!(jump(3.3).is_valid(-6.5, rest));
+        // Narrower than the threshold, but turning off below gravity: stuck on at rest
+        assert!(!jump(3.4).is_valid(-6.5, rest));
+        assert!(!jump(5.0).is_valid(-6.5, rest));
+
+        // Jumping then lying still again
+        let trace = [rest, -5.0, -4.0, -6.0, -8.0, rest, rest];
+        for tuning in [jump(1.5), jump(5.0)] {
+            let mut active = false;
+            for &accel in &trace {
+                active = above(accel, -6.5, tuning.band, active);
+            }
+            assert_eq!(active, !tuning.is_valid(-6.5, rest));
+        }
+    }
+
+    #[test]
+    fn control_debouncer_filters_gestures_but_not_the_button() {
+        let trace = hovering(0.05);
+        let mut noise = Noise(3);
+        let mut debouncer = ControlDebouncer::new();
+        let mut left_right = Vec::new();
+        let mut jump = Vec::new();
+        let mut previous = Control::default();
+        for (i, &roll) in trace.iter().enumerate() {
+            let was_left = previous.left_right == LeftRight::Left;
+            let control = Control {
+                left_right: if above(roll, THRESHOLD, TUNING.band, was_left) {
+                    LeftRight::Left
+                } else {
+                    LeftRight::None
+                },
+                jump: noise.next(1.0) > 0.0,
+                shoot: i % 2 == 0,
+                ..Control::default()
+            };
+            let now_ms = 1000 + i as u32 * SAMPLE_PERIOD_MS;
+            let debounced = debouncer.update(control, now_ms, &GESTURE_TUNING);
+            // The button is debounced by its own task
+            assert_eq!(debounced.shoot, control.shoot);
+            left_right.push(debounced.left_right);
+            jump.push(debounced.jump);
+            previous = debounced;
+        }
+        assert_eq!(transitions(LeftRight::None, left_right), 2);
+        let jumps = transitions(false, jump);
+        assert!(jumps > 1);
+        assert!(jumps <= (trace.len() as u32 * SAMPLE_PERIOD_MS / TUNING.hold_ms + 1) as usize);
+    }
+}
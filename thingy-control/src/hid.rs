@@ -0,0 +1,182 @@
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{self, NotifyValueError, RegisterError};
+use nrf_softdevice::ble::{Connection, SecurityMode, Uuid};
+use nrf_softdevice::Softdevice;
+
+use crate::{Control, LeftRight, UpDown};
+
+// Assigned numbers: https://www.bluetooth.com/specifications/assigned-numbers/
+const HID_SERVICE: Uuid = Uuid::new_16(0x1812);
+const HID_INFO: Uuid = Uuid::new_16(0x2a4a);
+const HID_REPORT_MAP: Uuid = Uuid::new_16(0x2a4b);
+const HID_CONTROL_POINT: Uuid = Uuid::new_16(0x2a4c);
+const HID_REPORT: Uuid = Uuid::new_16(0x2a4d);
+const HID_REPORT_REFERENCE: Uuid = Uuid::new_16(0x2908);
+
+const GAMEPAD_REPORT_ID: u8 = 0x01;
+const INPUT_REPORT_TYPE: u8 = 0x01;
+
+// bcdHID 1.11, no country code, normally connectable
+const HID_INFO_VALUE: [u8; 4] = [0x11, 0x01, 0x00, 0x02];
+
+// Gamepad with 5 buttons (shoot, jump, spin, double click, long press) and a X/Y D-pad
+// https://www.usb.org/sites/default/files/hut1_4.pdf
+#[rustfmt::skip]
+const GAMEPAD_REPORT_MAP: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x05, // Usage (Game Pad)
+    0xa1, 0x01, // Collection (Application)
+    0x85, GAMEPAD_REPORT_ID, //   Report ID
+    0x05, 0x09, //   Usage Page (Button)
+    0x19, 0x01, //   Usage Minimum (Button 1)
+    0x29, 0x05, //   Usage Maximum (Button 5)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x81, 0x03, //   Input (Constant) padding
+    0x05, 0x01, //   Usage Page (Generic Desktop)
+    0x09, 0x30, //   Usage (X)
+    0x09, 0x31, //   Usage (Y)
+    0x15, 0x81, //   Logical Minimum (-127)
+    0x25, 0x7f, //   Logical Maximum (127)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x02, //   Report Count (2)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xc0, // End Collection
+];
+
+// Input report as described by GAMEPAD_REPORT_MAP: [buttons, x, y]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct GamepadReport {
+    buttons: u8,
+    x: i8,
+    y: i8,
+}
+
+impl GamepadReport {
+    fn to_bytes(self) -> [u8; 3] {
+        [self.buttons, self.x as u8, self.y as u8]
+    }
+}
+
+impl From<&Control> for GamepadReport {
+    fn from(control: &Control) -> Self {
+        GamepadReport {
+            buttons: (control.shoot as u8)
+                | (control.jump as u8) << 1
+                | (control.spin as u8) << 2
+                | (control.double_click as u8) << 3
+                | (control.long_press as u8) << 4,
+            x: match control.left_right {
+                LeftRight::Left => -127,
+                LeftRight::None => 0,
+                LeftRight::Right => 127,
+            },
+            y: match control.up_down {
+                UpDown::Up => -127,
+                UpDown::None => 0,
+                UpDown::Down => 127,
+            },
+        }
+    }
+}
+
+// HID over GATT isn't supported by the gatt_service macro because the report
+// needs a Report Reference descriptor, so the service is built by hand.
+// based on: https://github.com/embassy-rs/nrf-softdevice/blob/master/examples/src/bin/ble_hid_keyboard_peripheral_builder.rs
+pub struct HidService {
+    input_report: u16,
+    input_report_cccd: u16,
+    control_point: u16,
+}
+
+pub enum HidServiceEvent {
+    InputReportCccdWrite { notifications: bool },
+    ControlPointWrite { suspend: bool },
+}
+
+impl HidService {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service_builder = ServiceBuilder::new(sd, HID_SERVICE)?;
+        // HID over GATT requires an encrypted link, like the control characteristics
+        let security = SecurityMode::JustWorks;
+
+        let hid_info = service_builder.add_characteristic(
+            HID_INFO,
+            Attribute::new(HID_INFO_VALUE).security(security),
+            Metadata::new(Properties::new().read()),
+        )?;
+        let _hid_info_handle = hid_info.build();
+
+        let report_map = service_builder.add_characteristic(
+            HID_REPORT_MAP,
+            Attribute::new(GAMEPAD_REPORT_MAP).security(security),
+            Metadata::new(Properties::new().read()),
+        )?;
+        let _report_map_handle = report_map.build();
+
+        let control_point = service_builder.add_characteristic(
+            HID_CONTROL_POINT,
+            Attribute::new([0u8]).security(security),
+            Metadata::new(Properties::new().write_without_response()),
+        )?;
+        let control_point_handle = control_point.build();
+
+        let mut input_report = service_builder.add_characteristic(
+            HID_REPORT,
+            Attribute::new(GamepadReport::default().to_bytes()).security(security),
+            Metadata::with_security(Properties::new().read().notify(), security),
+        )?;
+        let _input_report_reference = input_report.add_descriptor(
+            HID_REPORT_REFERENCE,
+            Attribute::new([GAMEPAD_REPORT_ID, INPUT_REPORT_TYPE]).security(security),
+        )?;
+        let input_report_handle = input_report.build();
+
+        let _service_handle = service_builder.build();
+
+        Ok(HidService {
+            input_report: input_report_handle.value_handle,
+            input_report_cccd: input_report_handle.cccd_handle,
+            control_point: control_point_handle.value_handle,
+        })
+    }
+
+    pub fn input_report_notify(
+        &self,
+        connection: &Connection,
+        report: &GamepadReport,
+    ) -> Result<(), NotifyValueError> {
+        gatt_server::notify_value(connection, self.input_report, &report.to_bytes())
+    }
+}
+
+impl gatt_server::Service for HidService {
+    type Event = HidServiceEvent;
+
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        if data.is_empty() {
+            return None;
+        }
+
+        if handle == self.input_report_cccd {
+            return Some(HidServiceEvent::InputReportCccdWrite {
+                notifications: data[0] & 0x01 != 0,
+            });
+        }
+
+        if handle == self.control_point {
+            // 0x00 suspend, 0x01 exit suspend
+            return Some(HidServiceEvent::ControlPointWrite {
+                suspend: data[0] == 0x00,
+            });
+        }
+
+        None
+    }
+}
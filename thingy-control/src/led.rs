@@ -0,0 +1,232 @@
+use defmt::*;
+use embassy_time::{Duration, Timer};
+use embedded_hal::i2c::I2c;
+
+use crate::battery::LOW_BATTERY_LEVEL;
+use crate::ble::Connections;
+use crate::bus::{i2c_fault, Bus, SharedI2c};
+use crate::calibration::CalibrationStatus;
+use crate::Server;
+
+const SX1509_ADDRESS: u8 = 0x3e;
+
+// SX1509 registers, bank A holds IO0 to IO7
+const REG_INPUT_DISABLE_A: u8 = 0x01;
+const REG_PULL_UP_A: u8 = 0x07;
+const REG_OPEN_DRAIN_A: u8 = 0x0b;
+const REG_DIR_A: u8 = 0x0f;
+const REG_DATA_A: u8 = 0x11;
+const REG_CLOCK: u8 = 0x1e;
+const REG_MISC: u8 = 0x1f;
+const REG_LED_DRIVER_ENABLE_A: u8 = 0x21;
+
+// Internal 2 MHz oscillator, LED driver clock divided by 2^(4-1) to 250 kHz
+const CLOCK_INTERNAL_OSC: u8 = 0x40;
+const MISC_LED_CLOCK_DIV_8: u8 = 0x40;
+
+// Thingy:52 lightwell LED, the IO sinks the LED current
+const LED_GREEN: u8 = 5;
+const LED_BLUE: u8 = 6;
+const LED_RED: u8 = 7;
+const LED_MASK: u8 = (1 << LED_RED) | (1 << LED_GREEN) | (1 << LED_BLUE);
+
+// The breathing capable pins IO4 to IO7 have 5 registers each from IO4 at 0x35:
+// TOn, IOn (intensity), Off (time and intensity), TRise and TFall
+fn led_registers(pin: u8) -> u8 {
+    0x35 + (pin - 4) * 5
+}
+
+const STATE_INTERVAL: Duration = Duration::from_millis(250);
+
+// Colours shown for the players 1 to 4 when the host sets one
+const PLAYER_COLOURS: [[u8; 3]; 4] = [[0, 0, 255], [255, 0, 0], [0, 255, 0], [255, 160, 0]];
+const DEFAULT_COLOUR: [u8; 3] = [255, 255, 255];
+
+// Times in steps of ~65 ms (from 1 to 15), 0 keeps the LED on (or doesn't fade).
+// The expander runs it, so a pattern costs no CPU once written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Pattern {
+    colour: [u8; 3], // red, green and blue intensity
+    on: u8,
+    off: u8,
+    fade: u8,
+}
+
+impl Pattern {
+    const fn solid(colour: [u8; 3]) -> Self {
+        Pattern {
+            colour,
+            on: 0,
+            off: 0,
+            fade: 0,
+        }
+    }
+}
+
+pub const OFF: Pattern = Pattern::solid([0, 0, 0]);
+// Slow blue blink
+const ADVERTISING: Pattern = Pattern {
+    colour: [0, 0, 255],
+    on: 3,
+    off: 15,
+    fade: 0,
+};
+// Fast yellow blink
+pub const CALIBRATING: Pattern = Pattern {
+    colour: [255, 160, 0],
+    on: 2,
+    off: 2,
+    fade: 0,
+};
+// Red breathing
+const LOW_BATTERY: Pattern = Pattern {
+    colour: [255, 0, 0],
+    on: 4,
+    off: 8,
+    fade: 8,
+};
+
+// Lightwell LED driven by the SX1509 LED driver (PWM, blink and breathing),
+// sharing the I2C bus with the IMU
+pub struct Leds<'a> {
+    i2c: SharedI2c,
+    bus: &'a Bus,
+    pattern: Pattern,
+    failed: bool, // a register may be wrong, the driver is set up again
+}
+
+impl<'a> Leds<'a> {
+    // Expects the expander already reset, only the LED pins are changed
+    pub fn new(bus: &'a Bus) -> Self {
+        let mut leds = Leds {
+            i2c: bus.device(),
+            bus,
+            pattern: OFF,
+            failed: false,
+        };
+        leds.init();
+        leds
+    }
+
+    fn init(&mut self) {
+        self.modify(REG_INPUT_DISABLE_A, LED_MASK, LED_MASK);
+        self.modify(REG_PULL_UP_A, LED_MASK, 0);
+        self.modify(REG_OPEN_DRAIN_A, LED_MASK, LED_MASK);
+        self.modify(REG_DIR_A, LED_MASK, 0);
+        self.write(REG_CLOCK, CLOCK_INTERNAL_OSC);
+        self.write(REG_MISC, MISC_LED_CLOCK_DIV_8);
+        self.modify(REG_LED_DRIVER_ENABLE_A, LED_MASK, LED_MASK);
+        self.modify(REG_DATA_A, LED_MASK, LED_MASK); // driver off until a pattern is shown
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        if let Err(e) = self.i2c.write(SX1509_ADDRESS, &[register, value]) {
+            warn!("could not write LED register {=u8:#x}", register);
+            self.bus.record(i2c_fault(&e));
+            self.failed = true;
+        }
+    }
+
+    fn modify(&mut self, register: u8, mask: u8, bits: u8) {
+        let mut value = [0u8];
+        if let Err(e) = self.i2c.write_read(SX1509_ADDRESS, &[register], &mut value) {
+            warn!("could not read LED register {=u8:#x}", register);
+            self.bus.record(i2c_fault(&e));
+            self.failed = true;
+            return;
+        }
+        self.write(register, (value[0] & !mask) | (bits & mask));
+    }
+
+    // All the channels are stopped while written and restarted at once to blink in sync.
+    // After a failed write the driver is set up again and the pattern written again.
+    pub fn show(&mut self, pattern: &Pattern) {
+        if self.failed {
+            self.failed = false;
+            self.init();
+        } else if self.pattern == *pattern {
+            return;
+        }
+        debug!("led: {:?}", pattern);
+        self.modify(REG_DATA_A, LED_MASK, LED_MASK);
+
+        let mut enabled = 0;
+        let pins = [LED_RED, LED_GREEN, LED_BLUE];
+        for (pin, intensity) in pins.into_iter().zip(pattern.colour) {
+            let base = led_registers(pin);
+            self.write(base, pattern.on);
+            self.write(base + 1, intensity);
+            self.write(base + 2, pattern.off << 3); // off intensity 0
+            self.write(base + 3, pattern.fade);
+            self.write(base + 4, pattern.fade);
+            if intensity > 0 {
+                enabled |= 1 << pin;
+            }
+        }
+
+        self.modify(REG_DATA_A, enabled, 0);
+        self.pattern = *pattern;
+    }
+}
+
+// GATT Service
+#[nrf_softdevice::gatt_service(uuid = "0000DAD4-0000-0000-0000-000000000000")]
+pub struct LedService {
+    #[characteristic(uuid = "0000DAD4-0000-0000-0000-000000000001", read, write)]
+    colour: [u8; 3], // red, green and blue intensity while connected
+
+    #[characteristic(uuid = "0000DAD4-0000-0000-0000-000000000002", read, write)]
+    player: u8, // 0 none, 1 to 4 sets the colour of that player
+}
+
+impl LedService {
+    // Called once every central is gone, the next session assigns its own players
+    pub fn reset(&self) {
+        unwrap!(self.colour_set(&DEFAULT_COLOUR));
+        unwrap!(self.player_set(&0));
+    }
+
+    // The LED task reads the colour from the characteristic.
+    // An invalid player is cleared, as if no player was set.
+    pub fn on_write(&self, event: LedServiceEvent) {
+        match event {
+            LedServiceEvent::ColourWrite(colour) => info!("led colour: {:?}", colour),
+            LedServiceEvent::PlayerWrite(0) => {
+                info!("no player");
+                unwrap!(self.colour_set(&DEFAULT_COLOUR));
+            }
+            LedServiceEvent::PlayerWrite(player) => match PLAYER_COLOURS.get(player as usize - 1) {
+                Some(colour) => {
+                    info!("player: {}", player);
+                    unwrap!(self.colour_set(colour));
+                }
+                None => {
+                    warn!("invalid player: {}", player);
+                    self.reset();
+                }
+            },
+        }
+    }
+}
+
+// Show the most important state: calibration, low battery, then the connection.
+// Waits first so the battery is measured before its level is checked.
+pub async fn led_task<'a>(leds: &mut Leds<'_>, server: &'a Server, connections: &'a Connections) {
+    loop {
+        Timer::after(STATE_INTERVAL).await;
+
+        let calibrating =
+            unwrap!(server.calibration.status_get()) == CalibrationStatus::Running as u8;
+        let battery_level = unwrap!(server.bas.battery_level_get());
+        let pattern = if calibrating {
+            CALIBRATING
+        } else if battery_level < LOW_BATTERY_LEVEL {
+            LOW_BATTERY
+        } else if connections.is_empty() {
+            ADVERTISING
+        } else {
+            Pattern::solid(unwrap!(server.led.colour_get()))
+        };
+        leds.show(&pattern);
+    }
+}
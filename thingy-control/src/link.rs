@@ -0,0 +1,159 @@
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::NoopMutex;
+use embassy_time::{Duration, Timer};
+use nrf_softdevice::ble::{Connection, PhySet};
+use nrf_softdevice::raw;
+
+use crate::ble::MAX_CONNECTIONS;
+use crate::watchdog::{Heartbeat, BEAT_INTERVAL};
+use crate::Server;
+
+// Requested once connected: the shortest intervals phones accept and no slave
+// latency, so a control change goes out on the next connection event
+const MIN_INTERVAL: u16 = 6; // 7.5 ms, in 1.25 ms units
+const MAX_INTERVAL: u16 = 12; // 15 ms
+const SLAVE_LATENCY: u16 = 0;
+const SUPERVISION_TIMEOUT: u16 = 400; // 4 s, in 10 ms units
+
+// Centrals may reject a parameters update during the service discovery
+const PARAMS_DELAY: Duration = Duration::from_secs(1);
+// The central may change the parameters again at any time
+const POLL_INTERVAL: Duration = BEAT_INTERVAL;
+
+pub const LINK_REPORT_LEN: usize = 12;
+
+// PHY and data length the central agreed to, the softdevice only tells them in
+// its events. Starts as the 1M PHY and 27 bytes packets of every new connection.
+#[derive(Clone, Copy, PartialEq, Format)]
+struct Negotiated {
+    tx_phy: u8, // BLE_GAP_PHY_1MBPS or BLE_GAP_PHY_2MBPS
+    rx_phy: u8,
+    max_tx_octets: u16, // link layer payload
+    max_rx_octets: u16,
+}
+
+impl Negotiated {
+    const fn new() -> Self {
+        Negotiated {
+            tx_phy: raw::BLE_GAP_PHY_1MBPS as u8,
+            rx_phy: raw::BLE_GAP_PHY_1MBPS as u8,
+            max_tx_octets: 27,
+            max_rx_octets: 27,
+        }
+    }
+}
+
+// By connection handle, the softdevice hands them out from 0
+static NEGOTIATED: NoopMutex<RefCell<[Negotiated; MAX_CONNECTIONS]>> =
+    NoopMutex::new(RefCell::new([Negotiated::new(); MAX_CONNECTIONS]));
+
+fn negotiated(handle: Option<u16>) -> Negotiated {
+    NEGOTIATED.lock(|negotiated| {
+        handle
+            .and_then(|handle| negotiated.borrow().get(handle as usize).copied())
+            .unwrap_or(Negotiated::new())
+    })
+}
+
+fn update(handle: u16, f: impl FnOnce(&mut Negotiated)) {
+    NEGOTIATED.lock(|negotiated| {
+        if let Some(negotiated) = negotiated.borrow_mut().get_mut(handle as usize) {
+            f(negotiated)
+        }
+    })
+}
+
+// Every softdevice event goes through it, before nrf-softdevice handles it
+pub fn on_event(evt: *const raw::ble_evt_t) {
+    let evt = unsafe { &*evt };
+    let gap_evt = unsafe { &evt.evt.gap_evt };
+    let handle = gap_evt.conn_handle;
+    match evt.header.evt_id as u32 {
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_CONNECTED => update(handle, |n| *n = Negotiated::new()),
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE => {
+            let phy_update = unsafe { &gap_evt.params.phy_update };
+            if phy_update.status == raw::BLE_HCI_STATUS_CODE_SUCCESS as u8 {
+                update(handle, |n| {
+                    n.tx_phy = phy_update.tx_phy;
+                    n.rx_phy = phy_update.rx_phy;
+                })
+            }
+        }
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE => {
+            let params = unsafe { &gap_evt.params.data_length_update.effective_params };
+            update(handle, |n| {
+                n.max_tx_octets = params.max_tx_octets;
+                n.max_rx_octets = params.max_rx_octets;
+            })
+        }
+        _ => {}
+    }
+}
+
+// Connection parameters, PHY and data length the central picked
+#[derive(Clone, Copy, PartialEq, Format)]
+pub struct LinkReport {
+    interval: u16,            // 1.25 ms units
+    latency: u16,             // connection events
+    supervision_timeout: u16, // 10 ms units
+    negotiated: Negotiated,
+}
+
+impl LinkReport {
+    // Little endian [interval u16, latency u16, supervision timeout u16, tx PHY u8,
+    // rx PHY u8, max tx octets u16, max rx octets u16]
+    pub fn to_bytes(&self) -> [u8; LINK_REPORT_LEN] {
+        let mut buf = [0u8; LINK_REPORT_LEN];
+        buf[0..2].copy_from_slice(&self.interval.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.latency.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.supervision_timeout.to_le_bytes());
+        buf[6] = self.negotiated.tx_phy;
+        buf[7] = self.negotiated.rx_phy;
+        buf[8..10].copy_from_slice(&self.negotiated.max_tx_octets.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.negotiated.max_rx_octets.to_le_bytes());
+        buf
+    }
+}
+
+// Asks the central for a fast link, then reports what it picked whenever it changes.
+// Runs for as long as the connection, next to its GATT server. Each poll is the
+// progress of an idle connection for the watchdog, the GATT events beat when busy.
+pub async fn link_task(mut conn: Connection, server: &Server, heartbeat: &Heartbeat) {
+    if let Err(e) = conn.phy_update(PhySet::M2, PhySet::M2) {
+        warn!("link: 2M PHY not requested: {:?}", e);
+    }
+    if let Err(e) = conn.data_length_update(None) {
+        warn!("link: data length extension not requested: {:?}", e);
+    }
+
+    Timer::after(PARAMS_DELAY).await;
+    let params = raw::ble_gap_conn_params_t {
+        min_conn_interval: MIN_INTERVAL,
+        max_conn_interval: MAX_INTERVAL,
+        slave_latency: SLAVE_LATENCY,
+        conn_sup_timeout: SUPERVISION_TIMEOUT,
+    };
+    if let Err(e) = conn.set_conn_params(params) {
+        warn!("link: connection parameters not requested: {:?}", e);
+    }
+
+    let mut previous = None;
+    loop {
+        // Once connected both intervals are the one in use
+        let params = conn.conn_params();
+        heartbeat.beat();
+        let report = LinkReport {
+            interval: params.min_conn_interval,
+            latency: params.slave_latency,
+            supervision_timeout: params.conn_sup_timeout,
+            negotiated: negotiated(conn.handle()),
+        };
+        if previous != Some(report) {
+            server.diagnostics.set_link(&report);
+            previous = Some(report);
+        }
+        Timer::after(POLL_INTERVAL).await;
+    }
+}
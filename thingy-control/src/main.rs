@@ -1,44 +1,125 @@
 #![feature(type_alias_impl_trait)]
-#![allow(incomplete_features)]
-#![feature(generic_const_exprs)]
 
 #![no_std]
 #![no_main]
 
+mod advertisement;
+mod analog;
+mod battery;
 mod ble;
-
-use core::cell::RefCell;
+mod bond;
+mod bus;
+mod button;
+mod calibration;
+mod config;
+mod control;
+mod crash;
+mod device_info;
+mod dfu;
+mod diagnostics;
+mod features;
+mod fusion;
+mod gesture;
+mod hid;
+mod led;
+mod link;
+mod model;
+mod raw_imu;
+mod sleep;
+mod speaker;
+mod watchdog;
+
+use core::cell::{Cell, RefCell};
 
 // math functions
-use libm::{atan2f, sqrtf};
+use libm::sqrtf;
 
 // logging
 use defmt::*;
-use {defmt_rtt as _, panic_probe as _};
+use defmt_rtt as _;
 
 // async
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::{raw::NoopRawMutex, NoopMutex};
-use embassy_time::{Delay, Timer};
-use futures::future::select;
-use futures::pin_mut;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Delay, Duration, Instant, Timer};
+use embassy_futures::join::{join, join4, join_array};
+use embassy_futures::select::{select, select4, Either};
 use static_cell::StaticCell;
 
 // HAL
-use embassy_embedded_hal::shared_bus::blocking::i2c::I2cDevice;
-use embassy_nrf::peripherals::{P0_11, TWISPI0};
+use embassy_nrf::peripherals::{P0_06, P0_11, TWISPI0};
+use embassy_nrf::pwm::{Prescaler, SimplePwm};
+use embassy_nrf::saadc::{self, Saadc};
 use embassy_nrf::twim::{self, Twim};
 use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::wdt::WatchdogHandle;
+use embassy_nrf::interrupt::InterruptExt;
 use embassy_nrf::{bind_interrupts, interrupt};
 
 // Ble
 use nrf_softdevice::ble::{gatt_server, Connection};
-use ble::{advertise_connectable, softdevice_setup};
+use nrf_softdevice::Flash;
+use ble::{advertise_connectable, device_name, softdevice_setup, Connections, MAX_CONNECTIONS};
+use bond::store_bonds;
+use bus::{i2c_fault, Bus, Fault, I2cBus, SharedI2c};
+use button::{button_task, ButtonState, BUTTON_EVENTS};
+use battery::{battery_task, BatteryService};
+use calibration::{
+    load_calibration, store_calibration, Calibration, CalibrationService, CalibrationStatus,
+    Calibrator, Progress,
+};
+use config::{config_task, load_settings, ConfigService, Settings};
+use control::{Control, LeftRight, UpDown};
+use crash::CrashReport;
+use device_info::DeviceInformationService;
+use dfu::{dfu_task, mark_booted, DfuService, DfuServiceEvent, DFU_WINDOW};
+use diagnostics::{diagnostics_task, DiagnosticsService, ResetReason};
+use hid::HidService;
+use led::{led_task, LedService, Leds, CALIBRATING, OFF};
+use link::link_task;
+use raw_imu::{RawBatch, RawImuService, RawSample};
+use speaker::{speaker_task, Sound, SpeakerService};
+use watchdog::{fed, watchdog_task, Heartbeat};
 
 // Sensor
 use sx1509::Sx1509; // IO expander
-use mpu9250::{device, Imu, Mpu9250, ImuMeasurements}; // IMU
-
+use analog::AnalogAxes;
+use features::{align_mag, features, tilt, wrap_angle};
+use fusion::{Madgwick, Vector3};
+use gesture::{above, ControlDebouncer};
+use model::{Path, MODEL};
+use mpu9250::{
+    device, AccelDataRate, Dlpf, GyroTempDataRate, InterruptConfig, InterruptEnable, Marg,
+    MargMeasurements, MpuConfig, Mpu9250,
+}; // IMU
+
+// IMU output data rate, the classifier runs on every new sample.
+// Must divide the 1 kHz internal sample rate (e.g. 100, 200, 500 or 1000 Hz).
+const IMU_SAMPLE_RATE_HZ: u16 = 200;
+const IMU_SAMPLE_RATE_DIVISOR: u8 = (1000 / IMU_SAMPLE_RATE_HZ - 1) as u8;
+
+// Orientation filter gain, how fast the accelerometer corrects the gyroscope drift
+const FUSION_BETA: f32 = 0.1;
+
+// Calibration keeps the device still for the gyroscope and accelerometer biases,
+// then rotated in every direction for the magnetometer.
+const CALIBRATION_STATIONARY_SAMPLES: u32 = 2 * IMU_SAMPLE_RATE_HZ as u32;
+const CALIBRATION_ROTATION_SAMPLES: u32 = 15 * IMU_SAMPLE_RATE_HZ as u32;
+// Holding the button this long at boot starts a calibration,
+// holding it even longer clears the bonds instead
+const CALIBRATION_PRESS: Duration = Duration::from_secs(3);
+const CLEAR_BONDS_PRESS: Duration = Duration::from_secs(10);
+// Without a data ready interrupt for this long the IMU is power cycled and set up again
+const IMU_SAMPLE_TIMEOUT: Duration = Duration::from_millis(100);
+// The MPU9250 is ready this long after its power is turned on
+const IMU_POWER_UP: Duration = Duration::from_millis(100);
+// Between two attempts to bring the sensors up
+const RECOVERY_DELAY: Duration = Duration::from_millis(100);
+// Rotating faster than this keeps the controller awake
+const SLEEP_MOTION_THRESHOLD: f32 = 0.2; // rad/s
 
 // When no GATT service is connected, the notification will fail.
 // This is not a problem, so we ignore the error and just log it.
@@ -49,99 +130,459 @@ fn unwrap_notify<T>(result: Result<(), T>, name: &str) {
     }
 }
 
-// Type for meaningfull code
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Format)]
-enum LeftRight {
-    Left,
-    #[default]
-    None,
-    Right,
-}
-
-impl From<LeftRight> for i8 {
-    fn from(lr: LeftRight) -> Self {
-        match lr {
-            LeftRight::Left => 1,
+impl Control {
+    // Bitfield with the whole state:
+    // bits 0-1 left_right (0 none, 1 left, 2 right), bits 2-3 up_down (0 none, 1 up, 2 down),
+    // bit 4 shoot, bit 5 jump, bit 6 spin, bits 7-8 turn (0 none, 1 left, 2 right),
+    // bit 9 double click and bit 10 long press
+    fn to_bits(&self) -> u16 {
+        let left_right_bits = |lr| match lr {
             LeftRight::None => 0,
-            LeftRight::Right => -1,
-        }
+            LeftRight::Left => 1,
+            LeftRight::Right => 2,
+        };
+        let left_right = left_right_bits(self.left_right);
+        let up_down = match self.up_down {
+            UpDown::None => 0,
+            UpDown::Up => 1,
+            UpDown::Down => 2,
+        };
+        left_right
+            | up_down << 2
+            | (self.shoot as u16) << 4
+            | (self.jump as u16) << 5
+            | (self.spin as u16) << 6
+            | left_right_bits(self.turn) << 7
+            | (self.double_click as u16) << 9
+            | (self.long_press as u16) << 10
     }
-}
-
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Format)]
-enum UpDown {
-    Up,
-    #[default]
-    None,
-    Down,
-}
 
-impl From<UpDown> for i8 {
-    fn from(ud: UpDown) -> Self {
-        match ud {
-            UpDown::Up => -1,
-            UpDown::None => 0,
-            UpDown::Down => 1,
+    // The button actions change without waiting for a sample
+    fn with_button(self, button: ButtonState) -> Self {
+        Control {
+            shoot: button.shoot,
+            double_click: button.double_click,
+            long_press: button.long_press,
+            ..self
         }
     }
-}
 
-#[derive(Debug, Default, Format)]
-pub struct Control {
-    left_right: LeftRight,
-    up_down: UpDown,
-    shoot: bool,
-    jump: bool,
-    spin: bool,
+    // Little endian [bits: u16, sequence: u16, timestamp in ms: u32]
+    fn pack(&self, sequence: u16, timestamp: Instant) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        buf[0..2].copy_from_slice(&self.to_bits().to_le_bytes());
+        buf[2..4].copy_from_slice(&sequence.to_le_bytes());
+        buf[4..8].copy_from_slice(&(timestamp.as_millis() as u32).to_le_bytes());
+        buf
+    }
 }
 
-// Concurrents decision tree manually evaluated
+// Concurrents decision tree manually evaluated, used without a trained model.
+// The previous state selects the threshold side of each hysteresis band.
+// `heading` is the tilt compensated rotation from the reference heading.
 fn my_incredible_machine_learning_model(
-    imu: ImuMeasurements<(f32, f32, f32)>,
-    button: bool,
+    imu: MargMeasurements<(f32, f32, f32)>,
+    gravity: Vector3,
+    heading: f32,
+    settings: &Settings,
+    previous: &Control,
+    button: ButtonState,
 ) -> Control {
     let accel = imu.accel;
     let gyro = imu.gyro;
-
-    let pitch = atan2f(accel.0, sqrtf(accel.1 * accel.1 + accel.2 * accel.2));
-    let roll = atan2f(accel.1, sqrtf(accel.0 * accel.0 + accel.2 * accel.2));
+    let tuning = &settings.tuning;
+
+    let (pitch, roll) = tilt(gravity);
+    let pitch_above = |value, state| {
+        above(
+            value,
+            settings.pitch_threshold,
+            tuning.up_down.band,
+            previous.up_down == state,
+        )
+    };
+    let roll_above = |value, state| {
+        above(
+            value,
+            settings.roll_threshold,
+            tuning.left_right.band,
+            previous.left_right == state,
+        )
+    };
+    let heading_above = |value, state| {
+        above(
+            value,
+            settings.turn_threshold,
+            tuning.turn.band,
+            previous.turn == state,
+        )
+    };
     Control {
         up_down: match pitch {
-            x if x < -0.3 => UpDown::Up,
-            x if x > 0.3 => UpDown::Down,
+            x if pitch_above(-x, UpDown::Up) => UpDown::Up,
+            x if pitch_above(x, UpDown::Down) => UpDown::Down,
             _ => UpDown::None,
         },
         left_right: match roll {
-            x if x > 0.3 => LeftRight::Left,
-            x if x < -0.3 => LeftRight::Right,
+            x if roll_above(x, LeftRight::Left) => LeftRight::Left,
+            x if roll_above(-x, LeftRight::Right) => LeftRight::Right,
             _ => LeftRight::None,
         },
-        shoot: button,
-        jump: accel.2 > -6.5,
-        spin: gyro.2 > 3.0,
+        shoot: button.shoot,
+        jump: above(
+            accel.2,
+            settings.jump_threshold,
+            tuning.jump.band,
+            previous.jump,
+        ),
+        spin: above(
+            gyro.2,
+            settings.spin_threshold,
+            tuning.spin.band,
+            previous.spin,
+        ),
+        // Same rotation direction as spin is left
+        turn: match heading {
+            x if heading_above(x, LeftRight::Left) => LeftRight::Left,
+            x if heading_above(-x, LeftRight::Right) => LeftRight::Right,
+            _ => LeftRight::None,
+        },
+        double_click: button.double_click,
+        long_press: button.long_press,
     }
 }
 
-// Read sensor, evaluate control and notify changes
-async fn control_task<'a>(
-    mpu: &mut Mpu9250<
-        device::I2cDevice<I2cDevice<'static, NoopRawMutex, Twim<'static, TWISPI0>>>,
-        Imu,
-    >,
+// Whether the button (active low) is pressed now and kept pressed for `duration`.
+// Waits at boot, longer than the watchdog timeout.
+async fn held(
     btn: &mut Input<'static, P0_11>,
+    watchdog: &mut WatchdogHandle,
+    duration: Duration,
+) -> bool {
+    btn.is_low()
+        && fed(watchdog, with_timeout(duration, btn.wait_for_high()))
+            .await
+            .is_err()
+}
+
+type ImuSensor = Mpu9250<device::I2cDevice<SharedI2c>, Marg>;
+
+fn imu_fault<E: embedded_hal::i2c::Error>(error: &mpu9250::Error<E>) -> Fault {
+    match error {
+        mpu9250::Error::BusError(e) => i2c_fault(e),
+        _ => Fault::Device,
+    }
+}
+
+// Resets the IO expander, its bank B powers the MPU9250. The LED pins are set up by Leds.
+async fn init_expander(bus: &Bus) -> Result<(), Fault> {
+    let mut i2c = bus.device();
+    let expander = Sx1509::new(&mut i2c, sx1509::DEFAULT_ADDRESS);
+    let mut expander = expander.take(i2c);
+    info!("Applying reset");
+    bus.retry(|| expander.borrow().software_reset(), i2c_fault)
+        .await?;
+
+    info!("Setting back direction");
+    bus.retry(|| expander.borrow().set_bank_a_direction(1), i2c_fault)
+        .await?;
+    bus.retry(|| expander.borrow().set_bank_b_direction(1), i2c_fault)
+        .await?;
+
+    info!("Setting pin 1 to output");
+    bus.retry(|| expander.borrow().set_bank_a_data(0x70), i2c_fault)
+        .await?;
+    bus.retry(|| expander.borrow().set_bank_b_data(0x01), i2c_fault)
+        .await?; // Turning on mpu pwd
+    Ok(())
+}
+
+// Turns the MPU9250 off and on again through the expander, without resetting the LEDs
+async fn power_cycle_imu(bus: &Bus) -> Result<(), Fault> {
+    let mut i2c = bus.device();
+    let expander = Sx1509::new(&mut i2c, sx1509::DEFAULT_ADDRESS);
+    let mut expander = expander.take(i2c);
+    bus.retry(|| expander.borrow().set_bank_b_direction(1), i2c_fault)
+        .await?;
+    bus.retry(|| expander.borrow().set_bank_b_data(0x00), i2c_fault)
+        .await?;
+    Timer::after(IMU_POWER_UP).await;
+    bus.retry(|| expander.borrow().set_bank_b_data(0x01), i2c_fault)
+        .await?;
+    Timer::after(IMU_POWER_UP).await;
+    Ok(())
+}
+
+async fn init_imu(bus: &Bus) -> Result<ImuSensor, Fault> {
+    // The sample rate divisor is only applied with the digital low pass filter (1 kHz)
+    let mut mpu = bus
+        .retry(
+            || {
+                Mpu9250::marg(
+                    bus.device(),
+                    &mut Delay,
+                    &mut MpuConfig::marg()
+                        .gyro_temp_data_rate(GyroTempDataRate::DlpfConf(Dlpf::_1))
+                        .accel_data_rate(AccelDataRate::DlpfConf(Dlpf::_1))
+                        .sample_rate_divisor(IMU_SAMPLE_RATE_DIVISOR),
+                )
+            },
+            imu_fault,
+        )
+        .await?;
+
+    let who_am_i = bus.retry(|| mpu.who_am_i(), i2c_fault).await?;
+    info!("Who mpu is?: {}", who_am_i);
+
+    info!("Enabling MPU data ready interrupt at {} Hz", IMU_SAMPLE_RATE_HZ);
+    // reading the data clears it
+    bus.retry(
+        || mpu.interrupt_config(InterruptConfig::INT_ANYRD_CLEAR),
+        i2c_fault,
+    )
+    .await?;
+    bus.retry(
+        || mpu.enable_interrupts(InterruptEnable::RAW_RDY_EN),
+        i2c_fault,
+    )
+    .await?;
+    Ok(mpu)
+}
+
+// Frees the bus, then power cycles the IMU and sets it up again in place.
+// Left as it is when that fails, the next sample timeout tries again.
+async fn recover_imu(mpu: &mut ImuSensor, bus: &Bus) {
+    bus.recover();
+    if let Err(fault) = power_cycle_imu(bus).await {
+        warn!("could not power cycle the IMU: {:?}", fault);
+        return;
+    }
+    match init_imu(bus).await {
+        Ok(new_mpu) => *mpu = new_mpu,
+        Err(fault) => warn!("could not initialise the IMU again: {:?}", fault),
+    }
+}
+
+// Run a whole calibration before anything else uses the IMU, e.g. at boot.
+// A sample that can't be read fails it.
+async fn calibrate(
+    mpu: &mut ImuSensor,
+    imu_int: &mut Input<'static, P0_06>,
+    bus: &Bus,
+) -> Option<Calibration> {
+    let mut calibrator =
+        Calibrator::new(CALIBRATION_STATIONARY_SAMPLES, CALIBRATION_ROTATION_SAMPLES);
+    loop {
+        if with_timeout(IMU_SAMPLE_TIMEOUT, imu_int.wait_for_rising_edge())
+            .await
+            .is_err()
+        {
+            warn!("calibration: no IMU sample");
+            return None;
+        }
+        let data = bus.retry(|| mpu.all(), i2c_fault).await.ok()?;
+        if let Progress::Done(result) = calibrator.update(data.accel, data.gyro, data.mag) {
+            return result;
+        }
+    }
+}
+
+// Read sensor, evaluate control and notify changes to every connected central.
+// Button changes are notified as they come, without waiting for a sample.
+// Returns once the controller was still for the sleep timeout.
+// I2C faults are retried, then the IMU is brought back in place.
+async fn control_task<'a>(
+    mpu: &mut ImuSensor,
+    imu_int: &mut Input<'static, P0_06>,
+    bus: &Bus,
+    heartbeat: &Heartbeat,
+    button_changed: &Channel<NoopRawMutex, ButtonState, BUTTON_EVENTS>,
+    settings: &Cell<Settings>,
+    calibration: &Cell<Calibration>,
+    calibration_request: &Signal<NoopRawMutex, ()>,
+    calibration_changed: &Signal<NoopRawMutex, Calibration>,
     server: &'a Server,
-    connection: &'a Connection,
+    connections: &'a Connections,
+    sounds: &'a Signal<NoopRawMutex, Sound>,
 ) {
+    let mut last_activity = Instant::now();
     let mut previous_control = Control::default();
+    let mut debouncer = ControlDebouncer::new();
+    let mut model_paths = [Path::new(); 5];
+    let mut calibrator = None;
+    let mut filter = Madgwick::new(1.0 / IMU_SAMPLE_RATE_HZ as f32, FUSION_BETA);
+    let mut reference_heading = None;
+    let mut connected = false;
+    let mut raw_batch = RawBatch::new();
+    let mut sequence: u16 = 0;
+    let mut button = ButtonState::default();
     loop {
-        // Improvement oportunity: use MPU interrupt
-        Timer::after_millis(100).await; // It's running an preemtive scheduler, so we need to yield
+        // Every iteration is progress, a failing IMU included: the controller stays
+        // connected with the button, and the diagnostics count the I2C errors
+        heartbeat.beat();
+        // MPU data ready interrupt, also yields to the other tasks.
+        // Without it the MPU lost its configuration or hangs the bus.
+        let imu_sample = with_timeout(IMU_SAMPLE_TIMEOUT, imu_int.wait_for_rising_edge());
+        match select(imu_sample, button_changed.receive()).await {
+            Either::First(Ok(())) => {}
+            Either::First(Err(_)) => {
+                warn!("no IMU sample");
+                recover_imu(mpu, bus).await;
+                continue;
+            }
+            Either::Second(new_button) => {
+                button = new_button;
+                // Held with the rest of the control during a calibration
+                if calibrator.is_none() {
+                    let timestamp = Instant::now();
+                    let current_control = previous_control.with_button(button);
+                    notify_change(
+                        &previous_control,
+                        &current_control,
+                        &mut sequence,
+                        timestamp,
+                        server,
+                        connections,
+                        sounds,
+                    );
+                    previous_control = current_control;
+                    last_activity = timestamp;
+                }
+                continue;
+            }
+        }
+        let timestamp = Instant::now();
+
+        let data = match bus.retry(|| mpu.all(), i2c_fault).await {
+            Ok(data) => data,
+            Err(_) => {
+                recover_imu(mpu, bus).await;
+                continue;
+            }
+        };
+        // Datasets are recorded calibrated, like the samples the model classifies
+        let (accel, gyro, mag) = calibration.get().apply(data.accel, data.gyro, data.mag);
+        let raw_sample = RawSample::new(timestamp, accel, gyro, mag);
+        server.raw.record(connections, &mut raw_batch, &raw_sample);
+
+        // A requested calibration takes the samples until it is done, the control is held meanwhile
+        if calibration_request.try_take().is_some() {
+            calibrator = Some(Calibrator::new(
+                CALIBRATION_STATIONARY_SAMPLES,
+                CALIBRATION_ROTATION_SAMPLES,
+            ));
+            server
+                .calibration
+                .set_status(CalibrationStatus::Running, connections);
+        }
+        if let Some(running) = calibrator.as_mut() {
+            if let Progress::Done(result) = running.update(data.accel, data.gyro, data.mag) {
+                calibrator = None;
+                let status = match result {
+                    Some(new_calibration) => {
+                        calibration.set(new_calibration);
+                        calibration_changed.signal(new_calibration);
+                        // Start over from the corrected samples
+                        filter = Madgwick::new(1.0 / IMU_SAMPLE_RATE_HZ as f32, FUSION_BETA);
+                        reference_heading = None;
+                        CalibrationStatus::Calibrated
+                    }
+                    None => CalibrationStatus::Failed,
+                };
+                server.calibration.set_status(status, connections);
+            }
+            last_activity = timestamp;
+            continue;
+        }
 
-        let data = mpu.all().expect("could not read all");
-        let current_control = my_incredible_machine_learning_model(data, btn.is_low());
-        notify_control(&previous_control, &current_control, server, connection);
+        let data = MargMeasurements {
+            accel,
+            gyro,
+            mag: align_mag(mag),
+            ..data
+        };
+
+        filter.update_marg(data.gyro, data.accel, data.mag);
+        let orientation = filter.quaternion();
+        let gravity = orientation.gravity();
+        // The heading when the first central connects (or after a calibration) is the neutral one
+        if connected == connections.is_empty() {
+            connected = !connected;
+            reference_heading = None;
+        }
+        let reference = *reference_heading.get_or_insert(orientation.yaw());
+        let heading = wrap_angle(orientation.yaw() - reference);
+        let (pitch, roll) = tilt(gravity);
+        let settings = settings.get();
+        let axes = AnalogAxes::new(pitch, roll, data.gyro.2, &settings);
+        let control = match &MODEL {
+            Some(model) => model.classify(
+                &features(gravity, heading, data.accel, data.gyro),
+                button,
+                &settings.tuning,
+                &mut model_paths,
+            ),
+            None => my_incredible_machine_learning_model(
+                data,
+                gravity,
+                heading,
+                &settings,
+                &previous_control,
+                button,
+            ),
+        };
+        let now_ms = timestamp.as_millis() as u32;
+        let current_control = debouncer.update(control, now_ms, &settings.tuning);
+        notify_change(
+            &previous_control,
+            &current_control,
+            &mut sequence,
+            timestamp,
+            server,
+            connections,
+            sounds,
+        );
+        connections.for_each(|connection| notify_axes(&axes, server, connection));
+
+        let (x, y, z) = data.gyro;
+        let moving = sqrtf(x * x + y * y + z * z) > SLEEP_MOTION_THRESHOLD;
+        // A connected central keeps it awake, the timeout runs once every central is gone
+        if previous_control != current_control || moving || !connections.is_empty() {
+            last_activity = timestamp;
+        }
         previous_control = current_control;
+
+        let sleep_timeout = settings.sleep_timeout;
+        if sleep_timeout > 0.0
+            && timestamp.duration_since(last_activity).as_millis() as f32 > sleep_timeout * 1000.0
+        {
+            info!("still for {} s", sleep_timeout);
+            return;
+        }
+    }
+}
+
+// Notify a control change to every connected central, with a new sequence number
+fn notify_change(
+    previous: &Control,
+    current: &Control,
+    sequence: &mut u16,
+    timestamp: Instant,
+    server: &Server,
+    connections: &Connections,
+    sounds: &Signal<NoopRawMutex, Sound>,
+) {
+    if previous == current {
+        return;
+    }
+    *sequence = sequence.wrapping_add(1);
+    if current.shoot && !previous.shoot {
+        sounds.signal(Sound::Shoot);
     }
+    connections.for_each(|connection| {
+        notify_state(current, *sequence, timestamp, server, connection);
+        notify_control(previous, current, server, connection);
+    });
 }
 
 // Notify changes
@@ -196,48 +637,112 @@ fn notify_control<'a>(
             "spin",
         );
     }
+
+    if previous_state.turn != current_state.turn {
+        info!("turn: {:?}", current_state.turn);
+        unwrap_notify(
+            server
+                .control
+                .turn_notify(connection, &current_state.turn.into()),
+            "turn",
+        );
+    }
+
+    // The HID report carries the whole state, so any change is sent at once
+    if previous_state != current_state {
+        unwrap_notify(
+            server
+                .hid
+                .input_report_notify(connection, &current_state.into()),
+            "hid",
+        );
+    }
+}
+
+// The whole state in one notification, so the host never sees a partial change
+fn notify_state<'a>(
+    state: &Control,
+    sequence: u16,
+    timestamp: Instant,
+    server: &'a Server,
+    connection: &'a Connection,
+) {
+    info!("state: {:?} sequence: {}", state, sequence);
+    unwrap_notify(
+        server
+            .control
+            .state_notify(connection, &state.pack(sequence, timestamp)),
+        "state",
+    );
+}
+
+// Analog axes are notified on every sample, so failures (e.g. a full TX queue
+// or notifications disabled) are expected and only traced to not flood the log.
+fn notify_axes<'a>(axes: &AnalogAxes, server: &'a Server, connection: &'a Connection) {
+    if server.control.axes_notify(connection, &axes.to_bytes()).is_err() {
+        trace!("axes notify error");
+    }
 }
 
 // GATT Service
 // This is a macro that generates a struct with the GATT service.
+// The control characteristics need an encrypted link, so the central must pair first.
 #[nrf_softdevice::gatt_service(uuid = "0000DAD0-0000-0000-0000-000000000000")]
 pub struct ControlService {
-    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000001", notify)]
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000001", notify, security = "just_works")]
     left_right: i8, // -1 left, 0 none, 1 right
 
-    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000002", notify)]
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000002", notify, security = "just_works")]
     up_down: i8, // -1 up, 0 none, 1 down
 
-    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000003", notify)]
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000003", notify, security = "just_works")]
     shoot: bool,
 
-    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000004", notify)]
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000004", notify, security = "just_works")]
     jump: bool,
 
-    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000005", notify)]
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000005", notify, security = "just_works")]
     spin: bool,
+
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000006", notify, security = "just_works")]
+    axes: [u8; 6], // i16 little endian pitch, roll and yaw rate
+
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000007", notify, security = "just_works")]
+    state: [u8; 8], // bitfield, sequence and timestamp, see Control::pack
+
+    #[characteristic(uuid = "0000DAD0-0000-0000-0000-000000000008", notify, security = "just_works")]
+    turn: i8, // same as left_right, from the heading
 }
 
 #[nrf_softdevice::gatt_server]
 pub struct Server {
     pub control: ControlService,
+    pub hid: HidService,
+    pub bas: BatteryService,
+    pub dis: DeviceInformationService,
+    pub config: ConfigService,
+    pub raw: RawImuService,
+    pub led: LedService,
+    pub speaker: SpeakerService,
+    pub dfu: DfuService,
+    pub calibration: CalibrationService,
+    pub diagnostics: DiagnosticsService,
 }
 
 
-// bind I2C interrupts
+// bind I2C and ADC interrupts
 bind_interrupts!(struct Irqs {
     SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0 => twim::InterruptHandler<TWISPI0>;
+    SAADC => saadc::InterruptHandler;
 });
 
 // Shared I2C bus
-static I2C_BUS: StaticCell<NoopMutex<RefCell<Twim<TWISPI0>>>> = StaticCell::new();
+static I2C_BUS: StaticCell<I2cBus> = StaticCell::new();
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     info!("Hello World!"); // Sanity check
 
-    const DEVICE_NAME: &'static [u8; 18] = b"Thingy Wii Control";
-
     // Fet the peripherals access crate.
 
     // Reduce interrupt priority because of softdevice
@@ -246,55 +751,235 @@ async fn main(spawner: Spawner) {
     config.time_interrupt_priority = interrupt::Priority::P2;
 
     let p = embassy_nrf::init(config);
+    // May still run from before a soft reset, so it's fed through the whole boot
+    let mut watchdog = watchdog::start(p.WDT);
     let mut btn = Input::new(p.P0_11, Pull::Up);
 
     // Turn on VDD Regulator
     let mut _vdd_pwd = Output::new(p.P0_30, Level::High, OutputDrive::Standard);
     Timer::after_millis(10).await;
 
-    let (sd, server) = softdevice_setup(&spawner, &DEVICE_NAME);
+    let reset_reason = ResetReason::take();
+    let crash_report = CrashReport::read();
+    let device_name = device_name();
+    let (sd, server, bonder) = softdevice_setup(&spawner, &device_name);
+    server.diagnostics.set_reset_reason(reset_reason);
+    server.diagnostics.set_crash_report(crash_report);
 
     info!("Initializing TWI...");
-    let config = twim::Config::default();
+    let mut config = twim::Config::default();
+    config.frequency = twim::Frequency::K400; // Reading all the IMU data must fit the sample period
     let i2c = Twim::new(p.TWISPI0, Irqs, p.P0_07, p.P0_08, config);
     let i2c_bus = I2C_BUS.init(NoopMutex::new(RefCell::new(i2c)));
+    let bus = Bus::new(i2c_bus);
+
+    // Nothing works without the sensors, so they are tried until they answer
+    while let Err(fault) = init_expander(&bus).await {
+        warn!("could not initialise the expander: {:?}", fault);
+        watchdog.pet();
+        bus.recover();
+        Timer::after(RECOVERY_DELAY).await;
+    }
+    Timer::after(IMU_POWER_UP).await;
+
+    info!("Initializing LEDs...");
+    let mut leds = Leds::new(&bus);
+
+    let mut imu_int = Input::new(p.P0_06, Pull::None);
+    let mut mpu = loop {
+        match init_imu(&bus).await {
+            Ok(mpu) => break mpu,
+            Err(fault) => {
+                warn!("could not initialise the IMU: {:?}", fault);
+                watchdog.pet();
+                bus.recover();
+                Timer::after(RECOVERY_DELAY).await;
+            }
+        }
+    };
+
+    info!("Initializing SAADC...");
+    interrupt::SAADC.set_priority(interrupt::Priority::P3);
+    let mut saadc_config = saadc::Config::default();
+    saadc_config.oversample = saadc::Oversample::OVER8X;
+    let mut battery_channel = saadc::ChannelConfig::single_ended(p.P0_28); // AIN4
+    battery_channel.gain = saadc::Gain::GAIN1_2;
+    let mut saadc = Saadc::new(p.SAADC, Irqs, saadc_config, [battery_channel]);
+    saadc.calibrate().await;
+
+    info!("Initializing speaker...");
+    let mut amplifier = Output::new(p.P0_29, Level::Low, OutputDrive::Standard);
+    let mut pwm = SimplePwm::new_1ch(p.PWM0, p.P0_27);
+    pwm.set_prescaler(Prescaler::Div16); // 1 MHz, so tones down to 31 Hz fit the 15 bits counter
+    pwm.disable();
+    let sounds = Signal::<NoopRawMutex, Sound>::new();
+
+    let connections = Connections::new();
+    server.led.reset();
+
+    info!("Loading configuration...");
+    let mut flash = Flash::take(sd);
+    let settings = Cell::new(load_settings(&mut flash).await);
+    let settings_changed = Signal::<NoopRawMutex, Settings>::new();
+    info!("settings: {:?}", settings.get());
+    server.config.set_settings(&settings.get());
+
+    info!("Loading bonds...");
+    bonder.load(&mut flash).await;
+
+    info!("Loading calibration...");
+    let stored_calibration = load_calibration(&mut flash).await;
+    let calibration = Cell::new(stored_calibration.unwrap_or_default());
+    let calibration_request = Signal::<NoopRawMutex, ()>::new();
+    let calibration_changed = Signal::<NoopRawMutex, Calibration>::new();
+    info!("calibration: {:?}", calibration.get());
+    let status = match stored_calibration {
+        Some(_) => CalibrationStatus::Calibrated,
+        None => CalibrationStatus::Uncalibrated,
+    };
+    server.calibration.set_status(status, &connections);
+
+    // Long press at boot: released after 3 s the device must then lie flat and still,
+    // then be rotated around for the calibration. Held for 10 s it clears the bonds.
+    if held(&mut btn, &mut watchdog, CALIBRATION_PRESS).await {
+        if held(&mut btn, &mut watchdog, CLEAR_BONDS_PRESS - CALIBRATION_PRESS).await {
+            bonder.clear();
+            store_bonds(&mut flash, bonder).await;
+            bonder.changed.reset();
+        } else {
+            server
+                .calibration
+                .set_status(CalibrationStatus::Running, &connections);
+            leds.show(&CALIBRATING);
+            let result = fed(&mut watchdog, calibrate(&mut mpu, &mut imu_int, &bus)).await;
+            let status = match result {
+                Some(new_calibration) => {
+                    calibration.set(new_calibration);
+                    store_calibration(&mut flash, &new_calibration).await;
+                    CalibrationStatus::Calibrated
+                }
+                None => CalibrationStatus::Failed,
+            };
+            server.calibration.set_status(status, &connections);
+        }
+    }
 
-    let mut i2c_dev1 = I2cDevice::new(i2c_bus);
-    let expander = Sx1509::new(&mut i2c_dev1, sx1509::DEFAULT_ADDRESS);
-    let mut expander = expander.take(i2c_dev1);
-    info!("Applying reset");
-    unwrap!(expander.borrow().software_reset());
-
-    info!("Setting back direction");
-    unwrap!(expander.borrow().set_bank_a_direction(1));
-    unwrap!(expander.borrow().set_bank_b_direction(1));
-
-    info!("Setting pin 1 to output");
-    unwrap!(expander.borrow().set_bank_a_data(0x70));
-    unwrap!(expander.borrow().set_bank_b_data(0x01)); // Turning on mpu pwd
+    // Booted fine, the flash is then shared by the storage tasks
+    let flash = Mutex::<NoopRawMutex, _>::new(flash);
+    mark_booted(&flash).await;
+    let dfu_commands = Channel::<NoopRawMutex, DfuServiceEvent, DFU_WINDOW>::new();
+
+    // Each slot serves one central at a time and advertises again once it disconnects.
+    // Only one slot advertises at a time, so advertising restarts while a slot is free.
+    let advertising = Mutex::<NoopRawMutex, ()>::new(());
+    let gatt_heartbeat = Heartbeat::new();
+    let connection_slot = |slot: usize| {
+        let connections = &connections;
+        let device_name = &device_name;
+        let advertising = &advertising;
+        let server = &server;
+        let settings = &settings;
+        let settings_changed = &settings_changed;
+        let calibration_request = &calibration_request;
+        let sounds = &sounds;
+        let dfu_commands = &dfu_commands;
+        let heartbeat = &gatt_heartbeat;
+        async move {
+            loop {
+                let conn = {
+                    let _advertising = advertising.lock().await;
+                    info!("advertising...");
+                    // Waiting for a central is the progress of an idle slot
+                    match heartbeat
+                        .beating(advertise_connectable(sd, device_name, bonder))
+                        .await
+                    {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            warn!("could not advertise: {:?}", e);
+                            Timer::after_secs(1).await;
+                            continue;
+                        }
+                    }
+                };
+                info!("advertising done! I have a connection on slot {}.", slot);
+                connections.insert(slot, &conn);
+                sounds.signal(Sound::Connect);
+
+                let gatt_fut = gatt_server::run(&conn, server, |e| {
+                    heartbeat.beat();
+                    match e {
+                        ServerEvent::Raw(e) => server.raw.on_write(slot, e),
+                        ServerEvent::Config(e) => {
+                            server.config.on_write(e, settings, settings_changed)
+                        }
+                        ServerEvent::Calibration(e) => {
+                            server.calibration.on_write(e, calibration_request)
+                        }
+                        ServerEvent::Led(e) => server.led.on_write(e),
+                        ServerEvent::Speaker(e) => server.speaker.on_write(e, sounds),
+                        ServerEvent::Dfu(e) => server.dfu.on_write(e, dfu_commands),
+                        ServerEvent::Diagnostics(e) => server.diagnostics.on_write(e),
+                        _ => info!("Connected/Disconnected"),
+                    }
+                });
+                // The link task never returns, the GATT server does on disconnection.
+                // It beats for an idle connection, the GATT events for a busy one.
+                select(gatt_fut, link_task(conn.clone(), server, heartbeat)).await;
+
+                info!("slot {} disconnected", slot);
+                connections.remove(slot);
+                server.raw.reset(slot);
+                sounds.signal(Sound::Disconnect);
+                if connections.is_empty() {
+                    server.led.reset();
+                }
+            }
+        }
+    };
+    let slots: [_; MAX_CONNECTIONS] = core::array::from_fn(connection_slot);
+    let connections_fut = join_array(slots);
+
+    let button_changed = Channel::<NoopRawMutex, ButtonState, BUTTON_EVENTS>::new();
+    let button_fut = button_task(&mut btn, &button_changed);
+
+    let control_heartbeat = Heartbeat::new();
+    let control_fut = control_task(
+        &mut mpu,
+        &mut imu_int,
+        &bus,
+        &control_heartbeat,
+        &button_changed,
+        &settings,
+        &calibration,
+        &calibration_request,
+        &calibration_changed,
+        &server,
+        &connections,
+        &sounds,
+    );
+    let input_fut = select(control_fut, button_fut);
+    let battery_fut = battery_task(&mut saadc, &server, &connections, &sounds);
+    let config_fut = config_task(&flash, &settings_changed, &calibration_changed, bonder);
+    let dfu_fut = dfu_task(&flash, &server, &connections, &dfu_commands);
+    let storage_fut = join(config_fut, dfu_fut);
+
+    let led_fut = led_task(&mut leds, &server, &connections);
+    let speaker_fut = speaker_task(&mut pwm, &mut amplifier, &settings, &sounds);
+    let diagnostics_fut = diagnostics_task(&bus, &server, &connections);
+    let watchdog_fut = watchdog_task(watchdog, &control_heartbeat, &gatt_heartbeat);
+    let health_fut = join(diagnostics_fut, watchdog_fut);
+    let feedback_fut = join4(battery_fut, led_fut, speaker_fut, health_fut);
+
+    // Only the control task returns, when it's time to sleep. Dropping the
+    // other tasks stops advertising, the centrals are told to disconnect.
+    select4(connections_fut, input_fut, feedback_fut, storage_fut).await;
+    connections.for_each(|connection| {
+        let _ = connection.disconnect();
+    });
     Timer::after_millis(100).await;
 
-    let i2c_dev2 = I2cDevice::new(i2c_bus);
-
-    let mut mpu = Mpu9250::imu_default(i2c_dev2, &mut Delay).unwrap();
-
-    let who_am_i = mpu.who_am_i().expect("could not read who am i");
-    info!("Who mpu is?: {}", who_am_i);
-
-    loop {
-        info!("advertising...");
-        let conn = unwrap!(advertise_connectable(sd, &DEVICE_NAME).await);
-        info!("advertising done! I have a connection.");
-
-        let control_fut = control_task(&mut mpu, &mut btn, &server, &conn);
-
-        let gatt_fut = gatt_server::run(&conn, &server, |_e| {
-            info!("Connected/Disconnected");
-        });
-
-        pin_mut!(gatt_fut);
-        pin_mut!(control_fut);
-
-        select(gatt_fut, control_fut).await;
-    }
+    leds.show(&OFF);
+    sleep::wake_on_motion(&mut bus.device());
+    sleep::system_off();
 }
@@ -0,0 +1,155 @@
+// Gesture model trained by gesture-trainer from labelled recordings.
+// build.rs includes the generated trees, or `MODEL = None` without them.
+use crate::button::ButtonState;
+use crate::features::FEATURE_COUNT;
+use crate::gesture::GestureTuning;
+use crate::{Control, LeftRight, UpDown};
+
+// Longest path from the root to a leaf, bounds the time of a classification
+pub const MAX_DEPTH: usize = 10;
+
+// Trees are flat arrays, the root first
+#[allow(dead_code)] // only the generated model builds them
+#[derive(Debug, Clone, Copy)]
+pub enum Node {
+    // Goes left when the feature is below the threshold
+    Split {
+        feature: u8,
+        threshold: f32,
+        left: u16,
+        right: u16,
+    },
+    // Class encoded like the control characteristics
+    Leaf(i8),
+}
+
+// One tree per gesture, the button still gives the shoot and its own actions
+pub struct Model {
+    pub depth: usize,
+    pub left_right: &'static [Node],
+    pub up_down: &'static [Node],
+    pub jump: &'static [Node],
+    pub spin: &'static [Node],
+    pub turn: &'static [Node],
+}
+
+include!(concat!(env!("OUT_DIR"), "/gesture_model.rs"));
+
+const _: () = if let Some(model) = &MODEL {
+    assert!(model.depth <= MAX_DEPTH, "the gesture model is too deep");
+};
+
+// Hysteresis of a split, from the gesture whose threshold is in the unit of its
+// feature (see features.rs): the tilts, the heading, the accelerometer and the gyroscope
+fn band(feature: usize, tuning: &GestureTuning) -> f32 {
+    match feature {
+        0 => tuning.up_down.band,
+        1 => tuning.left_right.band,
+        2 => tuning.turn.band,
+        3..=5 => tuning.jump.band,
+        _ => tuning.spin.band,
+    }
+}
+
+// The splits a tree went through on the previous sample and the side it took
+#[derive(Clone, Copy)]
+pub struct Path {
+    steps: [(u16, bool); MAX_DEPTH + 1], // node and whether it went right
+    len: usize,
+}
+
+impl Path {
+    pub const fn new() -> Self {
+        Path {
+            steps: [(0, false); MAX_DEPTH + 1],
+            len: 0,
+        }
+    }
+}
+
+// Walks down to a leaf, a malformed tree gives the neutral class.
+// A split on the previous path only changes side once the feature is half a band
+// past the threshold. Which side is the gesture isn't known, so the band is centred
+// on the trained threshold, and a value hovering around it doesn't toggle the class.
+fn predict(
+    tree: &[Node],
+    features: &[f32; FEATURE_COUNT],
+    tuning: &GestureTuning,
+    path: &mut Path,
+) -> i8 {
+    let previous = *path;
+    path.len = 0;
+    let mut index = 0;
+    for depth in 0..=MAX_DEPTH {
+        match tree.get(index) {
+            Some(Node::Split {
+                feature,
+                threshold,
+                left,
+                right,
+            }) => {
+                let feature = *feature as usize;
+                let value = features.get(feature).copied().unwrap_or(0.0);
+                let right_side = match previous.steps[..previous.len].get(depth) {
+                    Some(&(node, went_right)) if node as usize == index => {
+                        let margin = band(feature, tuning) / 2.0;
+                        if went_right {
+                            value >= *threshold - margin
+                        } else {
+                            value >= *threshold + margin
+                        }
+                    }
+                    _ => value >= *threshold,
+                };
+                path.steps[depth] = (index as u16, right_side);
+                path.len = depth + 1;
+                index = if right_side { *right } else { *left } as usize;
+            }
+            Some(Node::Leaf(class)) => return *class,
+            None => break,
+        }
+    }
+    0
+}
+
+// One path per tree, in the order of the model
+pub type Paths = [Path; 5];
+
+fn left_right(class: i8) -> LeftRight {
+    match class {
+        1 => LeftRight::Left,
+        -1 => LeftRight::Right,
+        _ => LeftRight::None,
+    }
+}
+
+fn up_down(class: i8) -> UpDown {
+    match class {
+        -1 => UpDown::Up,
+        1 => UpDown::Down,
+        _ => UpDown::None,
+    }
+}
+
+impl Model {
+    pub fn classify(
+        &self,
+        features: &[f32; FEATURE_COUNT],
+        button: ButtonState,
+        tuning: &GestureTuning,
+        paths: &mut Paths,
+    ) -> Control {
+        let [left_right_path, up_down_path, jump_path, spin_path, turn_path] = paths;
+        let walk = |tree: &[Node], path: &mut Path| predict(tree, features, tuning, path);
+        Control {
+            left_right: left_right(walk(self.left_right, left_right_path)),
+            up_down: up_down(walk(self.up_down, up_down_path)),
+            shoot: button.shoot,
+            jump: walk(self.jump, jump_path) != 0,
+            spin: walk(self.spin, spin_path) != 0,
+            turn: left_right(walk(self.turn, turn_path)),
+            double_click: button.double_click,
+            long_press: button.long_press,
+        }
+    }
+}
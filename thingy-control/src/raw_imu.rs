@@ -0,0 +1,203 @@
+use core::cell::Cell;
+
+use defmt::*;
+use embassy_time::Instant;
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{self, RegisterError};
+use nrf_softdevice::ble::Uuid;
+use nrf_softdevice::Softdevice;
+
+use crate::ble::{Connections, MAX_CONNECTIONS};
+use crate::fusion::Vector3;
+
+// 0000DAD2-0000-0000-0000-0000000000XX, the softdevice takes the bytes little endian
+fn raw_imu_uuid(id: u8) -> Uuid {
+    Uuid::new_128(&[id, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xd2, 0xda, 0, 0])
+}
+const RAW_IMU_SERVICE: u8 = 0x00;
+const RAW_IMU_DATA: u8 = 0x01;
+const RAW_IMU_STREAMING: u8 = 0x02;
+
+// timestamp (u32) + accel, gyro and mag (3 x i16 each)
+const SAMPLE_SIZE: usize = 22;
+// Fits the 256 bytes ATT MTU configured in softdevice_setup, minus the 3 bytes ATT header
+const MAX_BATCH_SAMPLES: usize = 11;
+const ATT_HEADER_SIZE: usize = 3;
+
+// One sample in fixed point, little endian:
+// timestamp in µs, accel in cm/s² (up to the ±16 g full scale), gyro in mrad/s and mag in 0.1 µT
+pub struct RawSample {
+    timestamp: u32,
+    accel: [i16; 3],
+    gyro: [i16; 3],
+    mag: [i16; 3],
+}
+
+impl RawSample {
+    pub fn new(timestamp: Instant, accel: Vector3, gyro: Vector3, mag: Vector3) -> Self {
+        // float to int casts saturate, so out of range values are clamped
+        let scale = |v: Vector3, k: f32| [(v.0 * k) as i16, (v.1 * k) as i16, (v.2 * k) as i16];
+        RawSample {
+            timestamp: timestamp.as_micros() as u32,
+            accel: scale(accel, 100.0),
+            gyro: scale(gyro, 1000.0),
+            mag: scale(mag, 10.0),
+        }
+    }
+
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.timestamp.to_le_bytes());
+        for (i, value) in self
+            .accel
+            .iter()
+            .chain(&self.gyro)
+            .chain(&self.mag)
+            .enumerate()
+        {
+            buf[4 + 2 * i..6 + 2 * i].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+// Samples waiting to be notified together
+pub struct RawBatch {
+    buf: [u8; SAMPLE_SIZE * MAX_BATCH_SAMPLES],
+    samples: usize,
+}
+
+impl RawBatch {
+    pub const fn new() -> Self {
+        RawBatch {
+            buf: [0; SAMPLE_SIZE * MAX_BATCH_SAMPLES],
+            samples: 0,
+        }
+    }
+
+    fn push(&mut self, sample: &RawSample) {
+        let start = self.samples * SAMPLE_SIZE;
+        sample.write_to(&mut self.buf[start..start + SAMPLE_SIZE]);
+        self.samples += 1;
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buf[..self.samples * SAMPLE_SIZE]
+    }
+}
+
+// Opt-in stream of the raw IMU samples to record datasets,
+// built by hand because the sample batch has a variable length.
+// Each connection slot streams only if its central asked for it.
+pub struct RawImuService {
+    data: u16,
+    data_cccd: u16,
+    streaming: u16,
+    enabled: [Cell<bool>; MAX_CONNECTIONS],
+}
+
+pub enum RawImuServiceEvent {
+    DataCccdWrite { notifications: bool },
+    StreamingWrite(bool),
+}
+
+impl RawImuService {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service_builder = ServiceBuilder::new(sd, raw_imu_uuid(RAW_IMU_SERVICE))?;
+
+        let data = service_builder.add_characteristic(
+            raw_imu_uuid(RAW_IMU_DATA),
+            Attribute::new([0u8; SAMPLE_SIZE * MAX_BATCH_SAMPLES])
+                .variable_len((SAMPLE_SIZE * MAX_BATCH_SAMPLES) as u16),
+            Metadata::new(Properties::new().notify()),
+        )?;
+        let data_handle = data.build();
+
+        let streaming = service_builder.add_characteristic(
+            raw_imu_uuid(RAW_IMU_STREAMING),
+            Attribute::new([0u8]),
+            Metadata::new(Properties::new().write()),
+        )?;
+        let streaming_handle = streaming.build();
+
+        let _service_handle = service_builder.build();
+
+        Ok(RawImuService {
+            data: data_handle.value_handle,
+            data_cccd: data_handle.cccd_handle,
+            streaming: streaming_handle.value_handle,
+            enabled: Default::default(),
+        })
+    }
+
+    pub fn on_write(&self, slot: usize, event: RawImuServiceEvent) {
+        match event {
+            RawImuServiceEvent::DataCccdWrite { notifications } => {
+                info!("raw imu notifications on slot {}: {}", slot, notifications)
+            }
+            RawImuServiceEvent::StreamingWrite(enabled) => {
+                info!("raw imu streaming on slot {}: {}", slot, enabled);
+                self.enabled[slot].set(enabled);
+            }
+        }
+    }
+
+    // Streaming stops with the central of the slot, so it doesn't cost battery in normal play
+    pub fn reset(&self, slot: usize) {
+        self.enabled[slot].set(false);
+    }
+
+    // Add the sample to the batch and notify it once it fills the smallest negotiated MTU
+    pub fn record(&self, connections: &Connections, batch: &mut RawBatch, sample: &RawSample) {
+        if !self.enabled.iter().any(Cell::get) {
+            batch.samples = 0;
+            return;
+        }
+
+        batch.push(sample);
+
+        let mut att_mtu = u16::MAX;
+        connections.for_each_slot(|slot, connection| {
+            if self.enabled[slot].get() {
+                att_mtu = att_mtu.min(connection.att_mtu());
+            }
+        });
+        let mtu_samples = (att_mtu as usize).saturating_sub(ATT_HEADER_SIZE) / SAMPLE_SIZE;
+        if batch.samples < mtu_samples.clamp(1, MAX_BATCH_SAMPLES) {
+            return;
+        }
+
+        connections.for_each_slot(|slot, connection| {
+            if !self.enabled[slot].get() {
+                return;
+            }
+            if gatt_server::notify_value(connection, self.data, batch.bytes()).is_err() {
+                // The TX queue is full (or notifications are disabled),
+                // the batch is dropped and the gap shows in the timestamps
+                debug!("raw imu notify error");
+            }
+        });
+        batch.samples = 0;
+    }
+}
+
+impl gatt_server::Service for RawImuService {
+    type Event = RawImuServiceEvent;
+
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        if data.is_empty() {
+            return None;
+        }
+
+        if handle == self.data_cccd {
+            return Some(RawImuServiceEvent::DataCccdWrite {
+                notifications: data[0] & 0x01 != 0,
+            });
+        }
+
+        if handle == self.streaming {
+            return Some(RawImuServiceEvent::StreamingWrite(data[0] != 0));
+        }
+
+        None
+    }
+}
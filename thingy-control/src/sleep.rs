@@ -0,0 +1,89 @@
+use defmt::*;
+use embassy_nrf::pac;
+use embedded_hal::i2c::I2c;
+use nrf_softdevice::raw;
+
+use crate::bus::SharedI2c;
+
+const MPU9250_ADDRESS: u8 = 0x68;
+const AK8963_ADDRESS: u8 = 0x0c;
+
+// MPU9250 registers
+const ACCEL_CONFIG2: u8 = 0x1d;
+const LP_ACCEL_ODR: u8 = 0x1e;
+const WOM_THR: u8 = 0x1f;
+const INT_PIN_CFG: u8 = 0x37;
+const INT_ENABLE: u8 = 0x38;
+const INT_STATUS: u8 = 0x3a;
+const MOT_DETECT_CTRL: u8 = 0x69;
+const USER_CTRL: u8 = 0x6a;
+const PWR_MGMT_1: u8 = 0x6b;
+const PWR_MGMT_2: u8 = 0x6c;
+// AK8963 register
+const CNTL1: u8 = 0x0a;
+
+// Any axis moving more than 4 mg per LSB, sampled at 7.81 Hz
+const WOM_THRESHOLD: u8 = 20; // 80 mg
+const LP_ACCEL_ODR_7_81_HZ: u8 = 5;
+
+// Thingy:52 wake-up sources
+const MPU_INT_PIN: usize = 6;
+const BUTTON_PIN: usize = 11;
+
+// The accelerometer alone is kept in low power cycles and raises the interrupt on motion.
+// The I2C master is stopped and bypassed to power down the magnetometer too.
+pub fn wake_on_motion(i2c: &mut SharedI2c) {
+    for (address, register, value) in [
+        (MPU9250_ADDRESS, USER_CTRL, 0x00),       // I2C master off
+        (MPU9250_ADDRESS, INT_PIN_CFG, 0x22),     // latched interrupt, bypass
+        (AK8963_ADDRESS, CNTL1, 0x00),            // power down
+        (MPU9250_ADDRESS, PWR_MGMT_1, 0x00),      // awake, internal oscillator
+        (MPU9250_ADDRESS, PWR_MGMT_2, 0x07),      // gyroscope off
+        (MPU9250_ADDRESS, ACCEL_CONFIG2, 0x09),   // ACCEL_FCHOICE_B, 184 Hz bandwidth
+        (MPU9250_ADDRESS, INT_ENABLE, 0x40),      // wake on motion only
+        (MPU9250_ADDRESS, MOT_DETECT_CTRL, 0xc0), // compare to the previous sample
+        (MPU9250_ADDRESS, WOM_THR, WOM_THRESHOLD),
+        (MPU9250_ADDRESS, LP_ACCEL_ODR, LP_ACCEL_ODR_7_81_HZ),
+        (MPU9250_ADDRESS, PWR_MGMT_1, 0x20), // cycle
+    ] {
+        if i2c.write(address, &[register, value]).is_err() {
+            warn!("could not write sleep register {=u8:#x}", register);
+        }
+    }
+
+    // Clears a pending data ready interrupt, so it doesn't wake up right away
+    let mut status = [0u8];
+    if i2c
+        .write_read(MPU9250_ADDRESS, &[INT_STATUS], &mut status)
+        .is_err()
+    {
+        warn!("could not clear the MPU interrupt");
+    }
+}
+
+// System OFF until the MPU interrupt rises or the button is pressed, the
+// chip then resets and boots again. The RAM is lost, only the flash is kept.
+pub fn system_off() -> ! {
+    let p0 = unsafe { &*pac::P0::ptr() };
+    p0.pin_cnf[MPU_INT_PIN].write(|w| {
+        w.dir().input();
+        w.input().connect();
+        w.pull().disabled();
+        w.sense().high()
+    });
+    p0.pin_cnf[BUTTON_PIN].write(|w| {
+        w.dir().input();
+        w.input().connect();
+        w.pull().pullup();
+        w.sense().low()
+    });
+
+    info!("system off");
+    unsafe {
+        raw::sd_power_system_off();
+    }
+    // Only returns in debug interface mode, where System OFF is emulated
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
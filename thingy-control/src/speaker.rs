@@ -0,0 +1,112 @@
+use core::cell::Cell;
+
+use defmt::*;
+use embassy_nrf::gpio::Output;
+use embassy_nrf::peripherals::{P0_29, PWM0};
+use embassy_nrf::pwm::SimplePwm;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+
+use crate::config::Settings;
+
+// A tone, 0 Hz is a silence
+struct Note {
+    frequency_hz: u32,
+    duration_ms: u64,
+}
+
+const fn note(frequency_hz: u32, duration_ms: u64) -> Note {
+    Note {
+        frequency_hz,
+        duration_ms,
+    }
+}
+
+const CONNECT: [Note; 2] = [note(880, 80), note(1320, 120)];
+const DISCONNECT: [Note; 2] = [note(1320, 80), note(880, 120)];
+const SHOOT: [Note; 1] = [note(2000, 30)];
+const LOW_BATTERY: [Note; 3] = [note(440, 150), note(0, 100), note(440, 150)];
+const DAMAGE: [Note; 2] = [note(300, 60), note(200, 120)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Sound {
+    Connect = 0,
+    Disconnect = 1,
+    Shoot = 2,
+    LowBattery = 3,
+    Damage = 4,
+}
+
+impl Sound {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Sound::Connect),
+            1 => Some(Sound::Disconnect),
+            2 => Some(Sound::Shoot),
+            3 => Some(Sound::LowBattery),
+            4 => Some(Sound::Damage),
+            _ => None,
+        }
+    }
+
+    fn notes(self) -> &'static [Note] {
+        match self {
+            Sound::Connect => &CONNECT,
+            Sound::Disconnect => &DISCONNECT,
+            Sound::Shoot => &SHOOT,
+            Sound::LowBattery => &LOW_BATTERY,
+            Sound::Damage => &DAMAGE,
+        }
+    }
+}
+
+// GATT Service
+#[nrf_softdevice::gatt_service(uuid = "0000DAD5-0000-0000-0000-000000000000")]
+pub struct SpeakerService {
+    #[characteristic(uuid = "0000DAD5-0000-0000-0000-000000000001", write)]
+    play: u8, // one of Sound, e.g. 4 when the player takes damage
+}
+
+impl SpeakerService {
+    pub fn on_write(&self, event: SpeakerServiceEvent, sounds: &Signal<NoopRawMutex, Sound>) {
+        match event {
+            SpeakerServiceEvent::PlayWrite(id) => match Sound::from_id(id) {
+                Some(sound) => sounds.signal(sound),
+                None => warn!("invalid sound: {}", id),
+            },
+        }
+    }
+}
+
+// Play the signaled sounds with a square wave, the volume is its duty cycle.
+// The amplifier is only powered while playing, a muted speaker (volume 0) skips them.
+pub async fn speaker_task(
+    pwm: &mut SimplePwm<'static, PWM0>,
+    amplifier: &mut Output<'static, P0_29>,
+    settings: &Cell<Settings>,
+    sounds: &Signal<NoopRawMutex, Sound>,
+) {
+    loop {
+        let sound = sounds.wait().await;
+        let volume = settings.get().volume;
+        if volume <= 0.0 {
+            continue;
+        }
+        debug!("playing {:?}", sound);
+
+        amplifier.set_high();
+        for note in sound.notes() {
+            if note.frequency_hz == 0 {
+                pwm.disable();
+            } else {
+                pwm.set_period(note.frequency_hz);
+                pwm.set_duty(0, (pwm.max_duty() as f32 * 0.5 * volume) as u16);
+                pwm.enable();
+            }
+            Timer::after_millis(note.duration_ms).await;
+        }
+        pwm.disable();
+        amplifier.set_low();
+    }
+}
@@ -0,0 +1,99 @@
+use core::cell::Cell;
+use core::future::Future;
+
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_nrf::peripherals::WDT;
+use embassy_nrf::wdt::{self, Watchdog, WatchdogHandle};
+use embassy_time::{Duration, Timer};
+
+// The chip resets when the watchdog isn't fed for this long
+const TIMEOUT_S: u32 = 4;
+const LFCLK_HZ: u32 = 32768;
+// Every task must have made progress since the previous check to feed it
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Shorter than the check interval, so a beating task never misses one
+pub const BEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+// Set by a task making progress, cleared by every watchdog check
+pub struct Heartbeat(Cell<bool>);
+
+impl Heartbeat {
+    pub const fn new() -> Self {
+        Heartbeat(Cell::new(false))
+    }
+
+    pub fn beat(&self) {
+        self.0.set(true);
+    }
+
+    fn take(&self) -> bool {
+        self.0.replace(false)
+    }
+
+    // Beats while `task` is polled, for waits which are progress themselves
+    // however long they last, e.g. advertising until a central connects
+    pub async fn beating<F: Future>(&self, task: F) -> F::Output {
+        let beats = async {
+            loop {
+                self.beat();
+                Timer::after(BEAT_INTERVAL).await;
+            }
+        };
+        match select(task, beats).await {
+            Either::First(output) => output,
+            Either::Second(_) => unreachable!(),
+        }
+    }
+}
+
+// Started first thing at boot, the boot feeds it until `watchdog_task` takes over.
+// A soft reset doesn't stop it: after a panic or a firmware update it's still
+// running, fed by the bootloader while it swapped the images.
+pub fn start(wdt: WDT) -> WatchdogHandle {
+    let mut config = wdt::Config::default();
+    config.timeout_ticks = TIMEOUT_S * LFCLK_HZ;
+    config.run_during_sleep = true;
+    config.run_during_debug_halt = false;
+    match Watchdog::try_new(wdt, config) {
+        Ok((_watchdog, [handle])) => handle,
+        Err(_) => {
+            // Left running by a firmware with another configuration, it can't be changed
+            warn!("watchdog already running, keeping its configuration");
+            unsafe { WatchdogHandle::steal(0) }
+        }
+    }
+}
+
+// Feeds the watchdog while `task` is polled, for the boot steps waiting longer than
+// the timeout before any task runs, e.g. the long press and the calibration
+pub async fn fed<F: Future>(handle: &mut WatchdogHandle, task: F) -> F::Output {
+    let feeding = async {
+        loop {
+            handle.pet();
+            Timer::after(CHECK_INTERVAL).await;
+        }
+    };
+    match select(task, feeding).await {
+        Either::First(output) => output,
+        Either::Second(_) => unreachable!(),
+    }
+}
+
+// Feeds the watchdog only when the control task and the connection slots both
+// made progress since the previous check, a stalled one resets the chip
+pub async fn watchdog_task(mut handle: WatchdogHandle, control: &Heartbeat, gatt: &Heartbeat) {
+    loop {
+        Timer::after(CHECK_INTERVAL).await;
+        let control_alive = control.take();
+        let gatt_alive = gatt.take();
+        if control_alive && gatt_alive {
+            handle.pet();
+        } else {
+            warn!(
+                "watchdog: not fed, control alive: {}, gatt alive: {}",
+                control_alive, gatt_alive
+            );
+        }
+    }
+}